@@ -0,0 +1,391 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::str::FromStr;
+
+use chrono::{Date, NaiveDate, Utc};
+use serenity::model::id::{ChannelId, RoleId, UserId};
+use thiserror::Error;
+
+use crate::stats::{month_start, week_start, year_start};
+
+#[derive(Debug, Error)]
+pub enum ArgError {
+    #[error("unterminated quote")]
+    UnterminatedQuote,
+    #[error("`--{0}` expects a value")]
+    MissingValue(String),
+    #[error("invalid {0} `{1}`")]
+    InvalidValue(String, String),
+    #[error("unknown flag `--{0}`")]
+    UnknownFlag(String),
+    #[error("unexpected argument `{0}`")]
+    Unexpected(String),
+}
+
+/// The arguments of a command, split into positional words and `--name value` flags.
+///
+/// Words are separated by any amount of whitespace, a double quote at the start of a word groups words
+/// until the next double quote, a single quote only does so if another one follows, so that `'Tis` stays a word.
+/// A backslash escapes the next character. Mentions and channel references are kept as single words.
+/// A flag takes the following word as its value unless it is written as `--name=value`,
+/// quoted words are never taken as flags.
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    words: Vec<String>,
+    flags: Vec<(String, Option<String>)>,
+}
+
+/// Splits `line` into words, remembering which ones were quoted
+fn tokenize(line: &str) -> Result<Vec<(String, bool)>, ArgError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars();
+    let mut token: Option<(String, bool)> = None;
+    let mut quote: Option<char> = None;
+
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', _) => {
+                let escaped = chars.next().unwrap_or('\\');
+                token.get_or_insert_with(Default::default).0.push(escaped);
+            },
+            (c, Some(q)) if c == q => quote = None,
+            (c, Some(_)) => token.get_or_insert_with(Default::default).0.push(c),
+            ('"', None) if token.is_none() => {
+                quote = Some(c);
+                token.get_or_insert_with(Default::default).1 = true;
+            },
+            ('\'', None) if token.is_none() && chars.as_str().contains('\'') => {
+                quote = Some(c);
+                token.get_or_insert_with(Default::default).1 = true;
+            },
+            (c, None) if c.is_whitespace() => tokens.extend(token.take()),
+            (c, None) => token.get_or_insert_with(Default::default).0.push(c),
+        }
+    }
+
+    if quote.is_some() {
+        return Err(ArgError::UnterminatedQuote);
+    }
+
+    tokens.extend(token);
+    Ok(tokens)
+}
+
+impl Args {
+    /// Arguments that are split already, the words are taken as they are
+    pub fn new(words: Vec<String>, flags: Vec<(String, Option<String>)>) -> Self {
+        Self { words, flags }
+    }
+
+    pub fn parse(line: &str) -> Result<Self, ArgError> {
+        let mut args = Args::default();
+        let mut tokens = tokenize(line)?.into_iter().peekable();
+
+        while let Some((token, quoted)) = tokens.next() {
+            match token.strip_prefix("--") {
+                Some(flag) if !quoted && !flag.is_empty() => {
+                    let (name, value) = match flag.find('=') {
+                        Some(pos) => (flag[..pos].to_string(), Some(flag[pos + 1..].to_string())),
+                        None => {
+                            let takes_next = tokens.peek()
+                                .map(|(next, quoted)| *quoted || !next.starts_with("--"))
+                                .unwrap_or(false);
+
+                            (flag.to_string(), if takes_next { tokens.next().map(|(next, _)| next) } else { None })
+                        },
+                    };
+
+                    args.flags.push((name, value));
+                },
+                _ => args.words.push(token),
+            }
+        }
+
+        Ok(args)
+    }
+
+    pub fn first(&self) -> Option<&str> {
+        self.get(0)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.words.get(index).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Fails on the first word after the first `n`
+    pub fn at_most(&self, n: usize) -> Result<(), ArgError> {
+        match self.words.get(n) {
+            Some(word) => Err(ArgError::Unexpected(word.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// The first `n` words, with all flags
+    pub fn truncated(&self, n: usize) -> Args {
+        Args {
+            words: self.words.iter().take(n).cloned().collect(),
+            flags: self.flags.clone(),
+        }
+    }
+
+    /// The arguments without the first word, used to hand arguments to subcommands
+    pub fn shift(&self) -> Args {
+        Args {
+            words: self.words.iter().skip(1).cloned().collect(),
+            flags: self.flags.clone(),
+        }
+    }
+
+    /// The value of the flag `--name` parsed as `T`, the last one wins if it was given more than once
+    pub fn flag<T: FromStr>(&self, name: &str) -> Result<Option<T>, ArgError> {
+        match self.flags.iter().rev().find(|(flag, _)| flag == name) {
+            Some((_, Some(value))) => value.parse()
+                .map(Some)
+                .map_err(|_| ArgError::InvalidValue(format!("value for `--{}`", name), value.clone())),
+            Some((_, None)) => Err(ArgError::MissingValue(name.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Fails on the first flag that is not in `known`
+    pub fn check_flags(&self, known: &[&str]) -> Result<(), ArgError> {
+        match self.flags.iter().find(|(flag, _)| !known.contains(&flag.as_str())) {
+            Some((flag, _)) => Err(ArgError::UnknownFlag(flag.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// The word at `index` parsed as `T`, `what` names the argument in the error
+    pub fn word<T: FromStr>(&self, index: usize, what: &str) -> Result<Option<T>, ArgError> {
+        self.word_with(index, what, |word| word.parse().ok())
+    }
+
+    /// The word at `index` turned into a `T` by `parse`, `what` names the argument in the error
+    pub fn word_with<T, F>(&self, index: usize, what: &str, parse: F) -> Result<Option<T>, ArgError>
+        where F: FnOnce(&str) -> Option<T> {
+
+        match self.words.get(index) {
+            Some(word) => parse(word)
+                .map(Some)
+                .ok_or_else(|| ArgError::InvalidValue(what.to_string(), word.clone())),
+            None => Ok(None),
+        }
+    }
+
+    /// Every word from `index` on turned into a `T` by `parse`, fails on the first one it rejects
+    pub fn words_with<T, F>(&self, index: usize, what: &str, mut parse: F) -> Result<Vec<T>, ArgError>
+        where F: FnMut(&str) -> Option<T> {
+
+        self.words.iter()
+            .skip(index)
+            .map(|word| parse(word).ok_or_else(|| ArgError::InvalidValue(what.to_string(), word.clone())))
+            .collect()
+    }
+
+    /// The words from `index` on joined by single spaces and turned into a `T` by `parse`,
+    /// so that names with spaces work without quotes
+    pub fn rest_with<T, F>(&self, index: usize, what: &str, parse: F) -> Result<Option<T>, ArgError>
+        where F: FnOnce(&str) -> Option<T> {
+
+        if index >= self.words.len() {
+            return Ok(None);
+        }
+
+        let rest = self.words[index..].join(" ");
+
+        match parse(&rest) {
+            Some(value) => Ok(Some(value)),
+            None => Err(ArgError::InvalidValue(what.to_string(), rest)),
+        }
+    }
+}
+
+/// Parses a role mention or a raw role id
+pub fn parse_role(arg: &str) -> Option<RoleId> {
+    serenity::utils::parse_role(arg)
+        .or_else(|| arg.parse().ok())
+        .map(RoleId)
+}
+
+/// Parses a channel reference or a raw channel id
+pub fn parse_channel(arg: &str) -> Option<ChannelId> {
+    serenity::utils::parse_channel(arg)
+        .or_else(|| arg.parse().ok())
+        .map(ChannelId)
+}
+
+/// Resolves a mention, a raw id or a (case insensitive) username to a known user
+pub fn resolve_user(arg: &str, trans: &BTreeMap<UserId, String>) -> Option<UserId> {
+    if let Some(uid) = serenity::utils::parse_username(arg).or_else(|| arg.parse().ok()) {
+        return Some(UserId(uid));
+    }
+
+    trans.iter()
+        .find(|(_, name)| name.as_str() == arg)
+        .or_else(|| trans.iter().find(|(_, name)| name.to_lowercase() == arg.to_lowercase()))
+        .map(|(uid, _)| *uid)
+}
+
+/// Parses `today`, `week`, `month`, `year` or an inclusive `<from>..<to>` range of dates
+pub fn parse_window(arg: &str) -> Option<Range<Date<Utc>>> {
+    let today = Utc::today();
+
+    match arg {
+        "today" => Some(today..today.succ()),
+        "week" => Some(week_start(today)..today.succ()),
+        "month" => Some(month_start(today)..today.succ()),
+        "year" => Some(year_start(today)..today.succ()),
+        range => {
+            let mut split = range.splitn(2, "..");
+            let from = NaiveDate::parse_from_str(split.next()?, "%Y-%m-%d").ok()?;
+            let to = NaiveDate::parse_from_str(split.next()?, "%Y-%m-%d").ok()?;
+
+            if from <= to {
+                Some(Date::from_utc(from, Utc)..Date::from_utc(to, Utc).succ())
+            } else {
+                None
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        Args::parse(line).unwrap().words
+    }
+
+    #[test]
+    fn splits_on_repeated_whitespace() {
+        assert_eq!(words("  stats \t top   week "), ["stats", "top", "week"]);
+        assert!(words("").is_empty());
+        assert!(words("   ").is_empty());
+    }
+
+    #[test]
+    fn quotes_group_words() {
+        assert_eq!(words(r#"stats user "some name" x"#), ["stats", "user", "some name", "x"]);
+        assert_eq!(words("whois 'some name'"), ["whois", "some name"]);
+        assert_eq!(words(r#"whois "it's""#), ["whois", "it's"]);
+        assert_eq!(words(r#"say """#), ["say", ""]);
+    }
+
+    #[test]
+    fn lone_single_quote_is_literal() {
+        assert_eq!(words("whois 'Tis"), ["whois", "'Tis"]);
+        assert_eq!(words("whois don't"), ["whois", "don't"]);
+    }
+
+    #[test]
+    fn unterminated_double_quote_fails() {
+        assert!(matches!(Args::parse(r#"whois "some name"#), Err(ArgError::UnterminatedQuote)));
+    }
+
+    #[test]
+    fn backslash_escapes() {
+        assert_eq!(words(r#"whois some\ name"#), ["whois", "some name"]);
+        assert_eq!(words(r#"whois "a \"b\"""#), ["whois", r#"a "b""#]);
+        assert_eq!(words(r"whois a\\b"), ["whois", r"a\b"]);
+        assert_eq!(words(r"whois a\"), ["whois", r"a\"]);
+    }
+
+    #[test]
+    fn bare_prefix_is_a_word() {
+        assert_eq!(words(">>"), [">>"]);
+        assert_eq!(words("help >>stats"), ["help", ">>stats"]);
+    }
+
+    #[test]
+    fn flags_take_the_next_word() {
+        let args = Args::parse("top --from 2024-01-01 --to 2024-02-01 extra").unwrap();
+
+        assert_eq!(args.words, ["top", "extra"]);
+        assert_eq!(args.flag::<String>("from").unwrap().as_deref(), Some("2024-01-01"));
+        assert_eq!(args.flag::<String>("to").unwrap().as_deref(), Some("2024-02-01"));
+    }
+
+    #[test]
+    fn flags_with_equals() {
+        let args = Args::parse("top --from=2024-01-01 week").unwrap();
+
+        assert_eq!(args.words, ["top", "week"]);
+        assert_eq!(args.flag::<String>("from").unwrap().as_deref(), Some("2024-01-01"));
+
+        let args = Args::parse("top --name=").unwrap();
+        assert_eq!(args.flag::<String>("name").unwrap().as_deref(), Some(""));
+    }
+
+    #[test]
+    fn flag_without_value() {
+        let args = Args::parse("top --from --to 2024-02-01").unwrap();
+
+        assert!(matches!(args.flag::<String>("from"), Err(ArgError::MissingValue(_))));
+        assert_eq!(args.flag::<String>("to").unwrap().as_deref(), Some("2024-02-01"));
+        assert!(matches!(Args::parse("top --from").unwrap().flag::<String>("from"), Err(ArgError::MissingValue(_))));
+    }
+
+    #[test]
+    fn quoted_words_are_not_flags() {
+        let args = Args::parse(r#"whois "--from" --to "--x""#).unwrap();
+
+        assert_eq!(args.words, ["whois", "--from"]);
+        assert_eq!(args.flag::<String>("to").unwrap().as_deref(), Some("--x"));
+        assert_eq!(words("whois --"), ["whois", "--"]);
+    }
+
+    #[test]
+    fn last_flag_wins() {
+        let args = Args::parse("--page 1 --page 2").unwrap();
+        assert_eq!(args.flag::<u32>("page").unwrap(), Some(2));
+    }
+
+    #[test]
+    fn invalid_and_unknown_flags() {
+        let args = Args::parse("--page x --other 1").unwrap();
+
+        assert!(matches!(args.flag::<u32>("page"), Err(ArgError::InvalidValue(_, _))));
+        assert!(matches!(args.check_flags(&["page"]), Err(ArgError::UnknownFlag(flag)) if flag == "other"));
+        assert!(args.check_flags(&["page", "other"]).is_ok());
+    }
+
+    #[test]
+    fn typed_words_name_the_argument() {
+        let args = Args::parse("12 x <@&34> <#56> some name").unwrap();
+
+        assert_eq!(args.word::<u32>(0, "page").unwrap(), Some(12));
+        assert_eq!(args.word::<u32>(9, "page").unwrap(), None);
+        assert!(matches!(args.word::<u32>(1, "page"), Err(ArgError::InvalidValue(what, word)) if what == "page" && word == "x"));
+
+        assert_eq!(args.word_with(2, "role", parse_role).unwrap(), Some(RoleId(34)));
+        assert_eq!(args.word_with(0, "role", parse_role).unwrap(), Some(RoleId(12)));
+        assert!(args.word_with(3, "role", parse_role).is_err());
+        assert_eq!(args.word_with(3, "channel", parse_channel).unwrap(), Some(ChannelId(56)));
+        assert!(args.word_with(2, "channel", parse_channel).is_err());
+
+        assert_eq!(args.rest_with(4, "user", |name| Some(name.to_string())).unwrap().as_deref(), Some("some name"));
+        assert_eq!(args.rest_with(6, "user", |name| Some(name.to_string())).unwrap(), None);
+
+        assert!(matches!(args.words_with(2, "role", parse_role), Err(ArgError::InvalidValue(_, word)) if word == "<#56>"));
+        assert_eq!(args.truncated(2).words, ["12", "x"]);
+        assert!(matches!(args.at_most(5), Err(ArgError::Unexpected(word)) if word == "name"));
+        assert!(args.at_most(6).is_ok());
+    }
+
+    #[test]
+    fn shift_keeps_flags() {
+        let args = Args::parse("stats top --from 2024-01-01").unwrap().shift();
+
+        assert_eq!(args.first(), Some("top"));
+        assert_eq!(args.flag::<String>("from").unwrap().as_deref(), Some("2024-01-01"));
+    }
+}
@@ -0,0 +1,195 @@
+use crate::permissions::PermissionLevel;
+
+/// Which subroutine of the bot runs a command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handler {
+    Stats,
+    Settings,
+    ForceUsernameUpdate,
+    Whois,
+    Export,
+    Privacy,
+    Help,
+}
+
+/// A command of the bot, everything `help` shows about it and what dispatch needs to know
+pub struct Command {
+    pub name: &'static str,
+    pub handler: Handler,
+    pub aliases: &'static [&'static str],
+    /// `<required>` and `[optional]` arguments, alternatives separated by `|`
+    pub args: &'static str,
+    pub description: &'static str,
+    /// level required unless the guild configured otherwise
+    pub permission: PermissionLevel,
+    /// subcommands that require a higher level than the command itself
+    pub restricted: &'static [(&'static str, PermissionLevel)],
+    /// names of the `--name value` flags the command accepts
+    pub flags: &'static [&'static str],
+}
+
+/// An option of the `settings` command
+pub struct Setting {
+    pub name: &'static str,
+    pub emoji: &'static str,
+    pub args: &'static str,
+    pub description: &'static str,
+}
+
+pub const COMMANDS: [Command; 7] = [
+    Command {
+        name: "stats",
+        handler: Handler::Stats,
+        aliases: &["stat"],
+        args: "user <user> | top [window] | friends [user] | channels [window] | channel <channel> [window] | graph [chart] [user] [png|svg] [<width>x<height>]",
+        description: "Time spent in voice channels. A window is `today`, `week`, `month`, `year` or `YYYY-MM-DD..YYYY-MM-DD`, \
+            `top`, `channels`, `channel` and `graph` also take `--from YYYY-MM-DD [--to YYYY-MM-DD]` instead. \
+            Charts are `total`, `time-per-day [grouped|stacked]`, `heatmap`, `friends` and `channels`, \
+            names with spaces can be quoted",
+        permission: PermissionLevel::Everyone,
+        restricted: &[],
+        flags: &["from", "to"],
+    },
+    Command {
+        name: "settings",
+        handler: Handler::Settings,
+        aliases: &["config"],
+        args: "[option] [value]",
+        description: "Shows or changes the settings of this server",
+        permission: PermissionLevel::Admin,
+        restricted: &[],
+        flags: &[],
+    },
+    Command {
+        name: "force-username-update",
+        handler: Handler::ForceUsernameUpdate,
+        aliases: &["refresh-names"],
+        args: "",
+        description: "Fetches the current username and nickname of every known user",
+        permission: PermissionLevel::Admin,
+        restricted: &[],
+        flags: &[],
+    },
+    Command {
+        name: "whois",
+        handler: Handler::Whois,
+        aliases: &["names"],
+        args: "<user>",
+        description: "Every username and nickname a user was seen with",
+        permission: PermissionLevel::Everyone,
+        restricted: &[],
+        flags: &[],
+    },
+    Command {
+        name: "export",
+        handler: Handler::Export,
+        aliases: &[],
+        args: "[me|server] [csv|json]",
+        description: "Exports your own or the whole server's stats as a file",
+        permission: PermissionLevel::Everyone,
+        restricted: &[("server", PermissionLevel::Admin)],
+        flags: &[],
+    },
+    Command {
+        name: "privacy",
+        handler: Handler::Privacy,
+        aliases: &[],
+        args: "optout | optin | forget | list",
+        description: "Stop being tracked, be tracked again, delete all your stats or list who opted out",
+        permission: PermissionLevel::Everyone,
+        restricted: &[("list", PermissionLevel::Admin)],
+        flags: &[],
+    },
+    Command {
+        name: "help",
+        handler: Handler::Help,
+        aliases: &["commands"],
+        args: "[command]",
+        description: "Lists all commands or explains one of them",
+        permission: PermissionLevel::Everyone,
+        restricted: &[],
+        flags: &[],
+    },
+];
+
+pub const SETTINGS: [Setting; 7] = [
+    Setting { name: "prefix", emoji: ":exclamation:", args: "<prefix>", description: "what commands start with" },
+    Setting { name: "page-size", emoji: ":page_facing_up:", args: "<number>", description: "users per leaderboard page" },
+    Setting { name: "graph-format", emoji: ":frame_photo:", args: "<png|svg>", description: "image format of charts" },
+    Setting { name: "graph-size", emoji: ":straight_ruler:", args: "<width>x<height>", description: "size of charts in pixels" },
+    Setting { name: "display-name", emoji: ":label:", args: "<username|nickname>", description: "which name users are shown with" },
+    Setting { name: "permission", emoji: ":lock:", args: "<command> [subcommand] <everyone|roles|admin|owner>", description: "who may use a command" },
+    Setting { name: "roles", emoji: ":busts_in_silhouette:", args: "<roles...|none>", description: "roles that hold the roles permission level" },
+];
+
+/// Finds a command by its name or one of its aliases
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter()
+        .find(|command| command.name == name || command.aliases.contains(&name))
+}
+
+/// Number of single character insertions, deletions and substitutions that turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+
+        prev = cur;
+    }
+
+    prev[b.len()]
+}
+
+/// The command that was most likely meant by a mistyped `name`, if any is close enough
+pub fn suggest(name: &str) -> Option<&'static str> {
+    let max_distance = name.chars().count() / 3 + 1;
+
+    COMMANDS.iter()
+        .flat_map(|command| std::iter::once(&command.name).chain(command.aliases.iter()).map(move |n| (command.name, *n)))
+        .map(|(command, n)| (command, edit_distance(name, n)))
+        .filter(|(_, distance)| *distance <= max_distance)
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(command, _)| command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_by_name_and_alias() {
+        assert_eq!(find("stats").map(|command| command.name), Some("stats"));
+        assert_eq!(find("stat").map(|command| command.name), Some("stats"));
+        assert_eq!(find("refresh-names").map(|command| command.name), Some("force-username-update"));
+        assert!(find("Stats").is_none());
+    }
+
+    #[test]
+    fn suggest_close_names() {
+        assert_eq!(suggest("stast"), Some("stats"));
+        assert_eq!(suggest("hlep"), Some("help"));
+        assert_eq!(suggest("setings"), Some("settings"));
+        assert_eq!(suggest("confg"), Some("settings"));
+    }
+
+    #[test]
+    fn suggest_nothing_for_unrelated_names() {
+        assert_eq!(suggest("xyzzy"), None);
+        assert_eq!(suggest(">>"), None);
+    }
+
+    #[test]
+    fn edit_distance_counts_single_edits() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("stats", "stats"), 0);
+    }
+}
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use serenity::model::channel::GuildChannel;
+use serenity::model::id::ChannelId;
+use serenity::model::voice::VoiceState;
+
+use crate::sessions::EndReason;
+
+/// Matches `name` against a pattern in which `*` stands for any sequence of characters
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+
+    let first = parts.next().unwrap_or("");
+    if !name.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &name[first.len()..];
+    let mut parts: Vec<&str> = parts.collect();
+
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Decides which users in voice channels are counted as online
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CountingRules {
+    pub ignored_channels: Vec<ChannelId>,
+    /// channel names matching one of these patterns are ignored, `*` matches anything
+    pub ignored_channel_patterns: Vec<String>,
+    /// all channels within these categories are ignored
+    pub ignored_categories: Vec<ChannelId>,
+    pub count_self_muted: bool,
+    pub count_server_muted: bool,
+    pub count_deafened: bool,
+    /// how many other humans have to be in the channel for a user to count
+    pub min_other_humans: usize,
+    pub ignore_bots: bool,
+}
+
+impl Default for CountingRules {
+    fn default() -> Self {
+        Self {
+            ignored_channels: Vec::new(),
+            ignored_channel_patterns: vec!["AFK*".to_string()],
+            ignored_categories: Vec::new(),
+            count_self_muted: true,
+            count_server_muted: true,
+            count_deafened: false,
+            min_other_humans: 0,
+            ignore_bots: true,
+        }
+    }
+}
+
+impl CountingRules {
+    pub fn is_ignored_channel(&self, channel: &GuildChannel) -> bool {
+        self.ignored_channels.contains(&channel.id)
+            || channel.category_id.map(|c| self.ignored_categories.contains(&c)).unwrap_or(false)
+            || self.ignored_channel_patterns.iter().any(|p| matches_pattern(p, &channel.name))
+    }
+
+    /// Returns `None` if the user counts as online, otherwise the reason they don't
+    pub fn check(&self, channel: &GuildChannel, state: &VoiceState, is_bot: bool, other_humans: usize) -> Option<EndReason> {
+        if is_bot && self.ignore_bots {
+            Some(EndReason::Bot)
+        } else if self.is_ignored_channel(channel) {
+            Some(EndReason::Afk)
+        } else if (state.deaf || state.self_deaf) && !self.count_deafened {
+            Some(EndReason::Deafened)
+        } else if (state.self_mute && !self.count_self_muted) || (state.mute && !self.count_server_muted) {
+            Some(EndReason::Muted)
+        } else if other_humans < self.min_other_humans {
+            Some(EndReason::TooFewHumans)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_pattern() {
+        assert!(matches_pattern("AFK*", "AFK"));
+        assert!(matches_pattern("AFK*", "AFK Lounge"));
+        assert!(!matches_pattern("AFK*", "The AFK"));
+        assert!(!matches_pattern("AFK*", "afk"));
+    }
+
+    #[test]
+    fn infix_pattern() {
+        assert!(matches_pattern("*afk*", "afk"));
+        assert!(matches_pattern("*afk*", "the afk room"));
+        assert!(matches_pattern("*afk*", "gone-afk"));
+        assert!(!matches_pattern("*afk*", "a f k"));
+    }
+
+    #[test]
+    fn pattern_without_wildcard_matches_exactly() {
+        assert!(matches_pattern("AFK", "AFK"));
+        assert!(!matches_pattern("AFK", "AFK "));
+        assert!(!matches_pattern("AFK", "AF"));
+        assert!(matches_pattern("", ""));
+        assert!(!matches_pattern("", "x"));
+    }
+
+    #[test]
+    fn parts_must_not_overlap() {
+        assert!(!matches_pattern("a*a", "a"));
+        assert!(matches_pattern("a*a", "aa"));
+        assert!(!matches_pattern("ab*ba", "aba"));
+        assert!(matches_pattern("ab*ba", "abba"));
+        assert!(!matches_pattern("*aa*aa*", "aaa"));
+        assert!(matches_pattern("*aa*aa*", "aaaa"));
+    }
+
+    #[test]
+    fn multiple_wildcards() {
+        assert!(matches_pattern("*", "anything"));
+        assert!(matches_pattern("a*b*c", "a-x-b-y-c"));
+        assert!(!matches_pattern("a*b*c", "a-c-b"));
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{Date, NaiveDate, Utc};
+use clap::Clap;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serenity::model::id::UserId;
+use thiserror::Error;
+
+use crate::format::{DataOpts, FileFormat};
+use crate::args::resolve_user;
+use crate::store::{self, delta_secs, StatStore, StoreError, Totals, DATE_FMT_STR};
+
+const CSV_HEADER: &str = "date,user_id,username,seconds,delta";
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("invalid argument: {0}")]
+    ArgError(String),
+    #[error("invalid csv in line {0}: {1}")]
+    CsvError(usize, String),
+    #[error("invalid json: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("storage error: {0}")]
+    StoreError(#[from] StoreError),
+    #[error("io error: {0}")]
+    IOError(#[from] std::io::Error),
+}
+
+pub type ExportResult<T> = Result<T, ExportError>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl FileFormat for ExportFormat {
+    const EXTENSIONS: &'static [(Self, &'static str)] = &[(ExportFormat::Csv, "csv"), (ExportFormat::Json, "json")];
+}
+
+/// The total of one user as of the end of one day
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExportRow {
+    /// formatted as `YYYY-MM-DD`
+    pub date: String,
+    pub user_id: UserId,
+    pub username: String,
+    /// cumulative online time
+    pub seconds: u64,
+    /// online time since the previous day with a total, 0 on the first day as that is the baseline, ignored on import
+    #[serde(default)]
+    pub delta: u64,
+}
+
+/// Collects the full history of the store, optionally only of the given users, ordered by date.
+/// `hidden` users are always left out.
+pub fn export_rows(store: &dyn StatStore, users: Option<&BTreeSet<UserId>>, hidden: &BTreeSet<UserId>) -> ExportResult<Vec<ExportRow>> {
+    let dates = match store.available_dates()? {
+        Some(dates) => dates,
+        None => return Ok(Vec::new()),
+    };
+
+    let usernames = store.usernames()?;
+    // the first snapshot is the baseline, just like for the time per day chart
+    let mut prev: Option<Totals> = None;
+    let mut rows = Vec::new();
+
+    for (date, totals) in store.totals(dates)? {
+        for (uid, &secs) in &totals {
+            if hidden.contains(uid) || users.map(|users| !users.contains(uid)).unwrap_or(false) {
+                continue;
+            }
+
+            let delta = match &prev {
+                Some(prev) => delta_secs(prev.get(uid).cloned().unwrap_or(0), secs),
+                None => 0,
+            };
+
+            rows.push(ExportRow {
+                date: date.format(DATE_FMT_STR).to_string(),
+                user_id: *uid,
+                username: usernames.get(uid).cloned().unwrap_or_else(|| format!("{}", uid)),
+                seconds: secs,
+                delta,
+            });
+        }
+
+        prev = Some(totals);
+    }
+
+    Ok(rows)
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Splits a single csv line into its fields, quoted fields may contain commas and doubled quotes
+fn csv_split(line: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+
+    if quoted {
+        return None;
+    }
+
+    fields.push(field);
+    Some(fields)
+}
+
+pub fn write_rows<W: Write>(w: W, rows: &[ExportRow], format: ExportFormat) -> ExportResult<()> {
+    let mut w = BufWriter::new(w);
+
+    match format {
+        ExportFormat::Json => serde_json::to_writer_pretty(&mut w, rows)?,
+        ExportFormat::Csv => {
+            writeln!(w, "{}", CSV_HEADER)?;
+
+            for row in rows {
+                writeln!(w, "{},{},{},{},{}", row.date, row.user_id, csv_escape(&row.username), row.seconds, row.delta)?;
+            }
+        },
+    }
+
+    w.flush()?;
+    Ok(())
+}
+
+pub fn read_rows<R: Read>(r: R, format: ExportFormat) -> ExportResult<Vec<ExportRow>> {
+    let r = BufReader::new(r);
+
+    match format {
+        ExportFormat::Json => Ok(serde_json::from_reader(r)?),
+        ExportFormat::Csv => {
+            let mut rows = Vec::new();
+            let mut lines = r.lines().enumerate();
+
+            while let Some((i, line)) = lines.next() {
+                let mut line = line?;
+                let line_nr = i + 1;
+
+                if line.trim().is_empty() || (i == 0 && line.trim() == CSV_HEADER) {
+                    continue;
+                }
+
+                // quoted fields may contain line breaks, so a record only ends with its last quote
+                let fields = loop {
+                    match csv_split(&line) {
+                        Some(fields) => break fields,
+                        None => match lines.next() {
+                            Some((_, next)) => {
+                                line.push('\n');
+                                line.push_str(&next?);
+                            },
+                            None => return Err(ExportError::CsvError(line_nr, "unterminated quote".to_string())),
+                        },
+                    }
+                };
+
+                let (date, user_id, username, seconds) = match &fields[..] {
+                    [date, user_id, username, seconds] | [date, user_id, username, seconds, _] => (date, user_id, username, seconds),
+                    _ => return Err(ExportError::CsvError(line_nr, format!("expected 5 fields, got {}", fields.len()))),
+                };
+
+                let parse_err = |what: &str| ExportError::CsvError(line_nr, format!("invalid {}", what));
+
+                rows.push(ExportRow {
+                    date: date.clone(),
+                    user_id: UserId(user_id.parse().map_err(|_| parse_err("user id"))?),
+                    username: username.clone(),
+                    seconds: seconds.parse().map_err(|_| parse_err("seconds"))?,
+                    delta: 0,
+                });
+            }
+
+            Ok(rows)
+        },
+    }
+}
+
+/// Writes the totals of `rows` into the store.
+///
+/// Totals of users that are not part of a day of the import are kept as they are,
+/// users that are not known to the store yet get the username of the import.
+/// Returns the number of days that were written.
+pub fn import_rows(store: &mut dyn StatStore, rows: &[ExportRow]) -> ExportResult<usize> {
+    let mut days: BTreeMap<Date<Utc>, Totals> = BTreeMap::new();
+    let mut usernames = store.usernames()?;
+    let mut new_users = false;
+
+    for row in rows {
+        let date = NaiveDate::parse_from_str(&row.date, DATE_FMT_STR)
+            .map_err(|_| ExportError::ArgError(format!("invalid date {:?}", row.date)))?;
+
+        days.entry(Date::from_utc(date, Utc))
+            .or_default()
+            .insert(row.user_id, row.seconds);
+
+        if let Entry::Vacant(entry) = usernames.entry(row.user_id) {
+            entry.insert(row.username.clone());
+            new_users = true;
+        }
+    }
+
+    for (date, imported) in &days {
+        let mut totals = store.totals(*date..date.succ())?
+            .pop()
+            .map(|(_date, totals)| totals)
+            .unwrap_or_default();
+
+        totals.extend(imported);
+        store.write_totals(*date, &totals)?;
+    }
+
+    if new_users {
+        store.write_usernames(&usernames)?;
+    }
+
+    Ok(days.len())
+}
+
+/// Exports the history of a data directory to csv or json
+#[derive(Clap)]
+pub struct ExportOpts {
+    #[clap(flatten)]
+    data: DataOpts,
+
+    /// csv or json, guessed from the output file if not given
+    #[clap(short = 'f', long = "format", possible_values = &["csv", "json"])]
+    format: Option<String>,
+
+    /// only include these users, by id or name
+    #[clap(short = 'u', long = "user", multiple_occurrences = true)]
+    users: Vec<String>,
+
+    /// output file, stdout if not given
+    #[clap(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+/// Imports a csv or json export into a data directory, stop the bot while doing so
+#[derive(Clap)]
+pub struct ImportOpts {
+    #[clap(flatten)]
+    data: DataOpts,
+
+    /// csv or json, guessed from the input file if not given
+    #[clap(short = 'f', long = "format", possible_values = &["csv", "json"])]
+    format: Option<String>,
+
+    /// file to import
+    #[clap(short = 'i', long = "input")]
+    input: PathBuf,
+}
+
+fn choose_format(format: Option<&str>, path: Option<&Path>) -> ExportResult<ExportFormat> {
+    match (format, path) {
+        (Some(format), _) => ExportFormat::from_extension(format),
+        (None, Some(path)) => ExportFormat::from_path(path),
+        (None, None) => Some(ExportFormat::Csv),
+    }
+    .ok_or_else(|| ExportError::ArgError("unknown format, use csv or json".to_string()))
+}
+
+pub fn export(opts: ExportOpts) -> ExportResult<()> {
+    let format = choose_format(opts.format.as_deref(), opts.output.as_deref())?;
+
+    if !opts.data.data_dir.is_dir() {
+        return Err(ExportError::ArgError(format!("data directory {:?} does not exist", opts.data.data_dir)));
+    }
+
+    let store = store::open(opts.data.storage, &opts.data.data_dir)?;
+
+    let users = if opts.users.is_empty() {
+        None
+    } else {
+        let trans = store.usernames()?;
+
+        Some(opts.users.iter()
+            .map(|arg| resolve_user(arg, &trans).ok_or_else(|| ExportError::ArgError(format!("unknown user {:?}", arg))))
+            .collect::<Result<BTreeSet<_>, _>>()?)
+    };
+
+    let rows = export_rows(store.as_ref(), users.as_ref(), &store.opted_out()?)?;
+
+    match &opts.output {
+        Some(path) => write_rows(File::create(path)?, &rows, format),
+        None => write_rows(std::io::stdout(), &rows, format),
+    }
+}
+
+pub fn import(opts: ImportOpts) -> ExportResult<()> {
+    let format = choose_format(opts.format.as_deref(), Some(&opts.input))?;
+    let rows = read_rows(File::open(&opts.input)?, format)?;
+
+    std::fs::create_dir_all(&opts.data.data_dir)?;
+    let mut store = store::open(opts.data.storage, &opts.data.data_dir)?;
+
+    let n_days = import_rows(store.as_mut(), &rows)?;
+    info!(rows = rows.len(), days = n_days; "imported stats");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::store::StorageBackend;
+
+    fn row(user_id: u64, username: &str, seconds: u64) -> ExportRow {
+        ExportRow { date: "2024-01-01".to_string(), user_id: UserId(user_id), username: username.to_string(), seconds, delta: 0 }
+    }
+
+    fn totals(entries: &[(u64, u64)]) -> Totals {
+        entries.iter().map(|(uid, secs)| (UserId(*uid), *secs)).collect()
+    }
+
+    fn round_trip(rows: &[ExportRow], format: ExportFormat) -> Vec<ExportRow> {
+        let mut buf = Vec::new();
+        write_rows(&mut buf, rows, format).unwrap();
+        read_rows(&buf[..], format).unwrap()
+    }
+
+    #[test]
+    fn csv_split_handles_quotes() {
+        assert_eq!(csv_split("a,b,,c").unwrap(), ["a", "b", "", "c"]);
+        assert_eq!(csv_split(r#"a,"b,c","d ""e""""#).unwrap(), ["a", "b,c", r#"d "e""#]);
+        assert!(csv_split(r#"a,"b"#).is_none());
+    }
+
+    #[test]
+    fn csv_round_trip_keeps_special_usernames() {
+        let rows = vec![
+            row(1, "plain", 10),
+            row(2, "with, comma", 20),
+            row(3, r#"with "quotes""#, 30),
+            row(4, "with\nnewline", 40),
+            row(5, "all, \"of\nthem\"", 50),
+        ];
+
+        let read = round_trip(&rows, ExportFormat::Csv);
+
+        assert_eq!(read.len(), rows.len());
+
+        for (read, row) in read.iter().zip(&rows) {
+            assert_eq!((&read.date, read.user_id, &read.username, read.seconds), (&row.date, row.user_id, &row.username, row.seconds));
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let rows = vec![row(1, "a\n\"b\"", 10)];
+        assert_eq!(round_trip(&rows, ExportFormat::Json)[0].username, rows[0].username);
+    }
+
+    #[test]
+    fn unterminated_quote_names_its_line() {
+        let csv = format!("{}\n2024-01-01,1,\"open,10,0\n", CSV_HEADER);
+        assert!(matches!(read_rows(csv.as_bytes(), ExportFormat::Csv), Err(ExportError::CsvError(2, _))));
+    }
+
+    #[test]
+    fn first_day_is_the_baseline() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store::open(StorageBackend::Json, dir.path()).unwrap();
+        let day = |d| Date::from_utc(NaiveDate::from_ymd_opt(2024, 1, d).unwrap(), Utc);
+
+        store.write_totals(day(1), &totals(&[(1, 100)])).unwrap();
+        store.write_totals(day(2), &totals(&[(1, 160), (2, 30)])).unwrap();
+        store.write_totals(day(3), &totals(&[(1, 20), (2, 30)])).unwrap();
+
+        let rows = export_rows(store.as_ref(), None, &BTreeSet::new()).unwrap();
+        let deltas: Vec<(&str, u64, u64)> = rows.iter().map(|r| (r.date.as_str(), r.user_id.0, r.delta)).collect();
+
+        assert_eq!(deltas, [
+            ("2024-01-01", 1, 0),
+            ("2024-01-02", 1, 60),
+            ("2024-01-02", 2, 30),
+            // a lower total means the counter was reset
+            ("2024-01-03", 1, 20),
+            ("2024-01-03", 2, 0),
+        ]);
+    }
+}
@@ -0,0 +1,43 @@
+use std::path::{Path, PathBuf};
+
+use clap::Clap;
+
+use crate::store::StorageBackend;
+
+/// A file format that is named after its extension
+pub trait FileFormat: Copy + PartialEq + 'static {
+    /// every format with its lowercase extension
+    const EXTENSIONS: &'static [(Self, &'static str)];
+
+    /// Parses an extension, case insensitive
+    fn from_extension(ext: &str) -> Option<Self> {
+        let ext = ext.to_lowercase();
+
+        Self::EXTENSIONS.iter()
+            .find(|(_, e)| *e == ext)
+            .map(|(format, _)| *format)
+    }
+
+    /// Guesses the format from the extension of `path`
+    fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        Self::from_extension(path.as_ref().extension()?.to_str()?)
+    }
+
+    fn extension(&self) -> &'static str {
+        Self::EXTENSIONS.iter()
+            .find(|(format, _)| format == self)
+            .map(|(_, e)| *e)
+            .expect("every format has an extension")
+    }
+}
+
+// where the command line tools find the stats of a guild, a doc comment would replace the about text of the subcommands
+#[derive(Clap)]
+pub struct DataOpts {
+    /// data directory of a single guild
+    #[clap(short = 'd', long = "data-dir")]
+    pub data_dir: PathBuf,
+
+    #[clap(long = "storage", default_value = "json", possible_values = &["json", "sqlite"])]
+    pub storage: StorageBackend,
+}
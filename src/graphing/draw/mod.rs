@@ -7,6 +7,12 @@ use std::ops::Range;
 
 mod util;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BarLayout {
+    Grouped,
+    Stacked,
+}
+
 pub fn time_total_graph<DB: DrawingBackend>(
     canvas: &mut DrawingArea<DB, Shift>,
     stats: Vec<(Date<Utc>, BTreeMap<String, u64>)>,
@@ -64,3 +70,213 @@ pub fn time_total_graph<DB: DrawingBackend>(
         .draw()
         .unwrap();
 }
+
+pub fn time_per_day_graph<DB: DrawingBackend>(
+    canvas: &mut DrawingArea<DB, Shift>,
+    deltas: Vec<(Date<Utc>, BTreeMap<String, u64>)>,
+    trans: BTreeMap<String, String>,
+    layout: BarLayout,
+) {
+    let days: Vec<Date<Utc>> = deltas.iter().map(|(date, _)| *date).collect();
+    let users: Vec<String> = util::split_stats(deltas.clone())
+        .into_iter()
+        .filter(|(_user, stst)| stst.iter().any(|(_d, t)| *t > 0))
+        .map(|(user, _)| user)
+        .collect();
+
+    let max_time = match layout {
+        BarLayout::Grouped => util::max_time(&deltas).unwrap_or(0),
+        BarLayout::Stacked => deltas
+            .iter()
+            .map(|(_date, stats)| stats.values().sum::<u64>())
+            .max()
+            .unwrap_or(0),
+    };
+
+    canvas.fill(&WHITE).unwrap();
+
+    let label_days = days.clone();
+    let x_label_fmt = move |x: &f64| {
+        label_days
+            .get(*x as usize)
+            .map(|d| d.format("%Y-%m-%d").to_string())
+            .unwrap_or_default()
+    };
+
+    let mut chart = ChartBuilder::on(canvas)
+        .caption("Time per day", (FontFamily::SansSerif, 50))
+        .margin_left(30)
+        .margin_right(30)
+        .x_label_area_size(30)
+        .y_label_area_size(30)
+        .build_cartesian_2d(
+            0f64..(days.len().max(1) as f64),
+            0f64..(max_time as f64 / 60.0 / 60.0).max(1.0),
+        )
+        .unwrap();
+
+    chart
+        .configure_mesh()
+        .disable_x_mesh()
+        .x_labels(days.len().min(15))
+        .x_label_formatter(&x_label_fmt)
+        .y_desc("hours")
+        .draw()
+        .unwrap();
+
+    let group_width = 0.8 / users.len().max(1) as f64;
+
+    for (i, user) in users.iter().enumerate() {
+        let color = util::uid_to_color(user);
+
+        let bars = deltas.iter().enumerate().filter_map(|(day, (_date, stats))| {
+            let ontime = *stats.get(user)? as f64 / 60.0 / 60.0;
+
+            let (x0, x1, y0) = match layout {
+                BarLayout::Grouped => {
+                    let x0 = day as f64 + 0.1 + i as f64 * group_width;
+                    (x0, x0 + group_width, 0.0)
+                }
+                BarLayout::Stacked => {
+                    let below: u64 = users[..i].iter().filter_map(|u| stats.get(u)).sum();
+                    (day as f64 + 0.1, day as f64 + 0.9, below as f64 / 60.0 / 60.0)
+                }
+            };
+
+            Some(Rectangle::new([(x0, y0), (x1, y0 + ontime)], color.filled()))
+        });
+
+        chart
+            .draw_series(bars)
+            .unwrap()
+            .label(
+                trans
+                    .get(user)
+                    .map(String::as_str)
+                    .unwrap_or("[[untranslatable]]"),
+            )
+            .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 20, y + 5)], color.filled()));
+    }
+
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()
+        .unwrap();
+}
+
+pub fn heatmap_graph<DB: DrawingBackend>(
+    canvas: &mut DrawingArea<DB, Shift>,
+    occupancy: [[f64; 24]; 7],
+    title: &str,
+) {
+    const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+    let max = occupancy
+        .iter()
+        .flat_map(|hours| hours.iter())
+        .cloned()
+        .fold(0.0, f64::max);
+
+    canvas.fill(&WHITE).unwrap();
+
+    let mut chart = ChartBuilder::on(canvas)
+        .caption(title, (FontFamily::SansSerif, 50))
+        .margin_left(30)
+        .margin_right(30)
+        .x_label_area_size(40)
+        .y_label_area_size(50)
+        .build_cartesian_2d((0i32..23).into_segmented(), (0i32..6).into_segmented())
+        .unwrap();
+
+    // monday is drawn at the top
+    let y_label_fmt = |y: &SegmentValue<i32>| match y {
+        SegmentValue::CenterOf(y) if (0..7).contains(y) => WEEKDAYS[(6 - *y) as usize].to_string(),
+        _ => String::new(),
+    };
+
+    let x_label_fmt = |x: &SegmentValue<i32>| match x {
+        SegmentValue::CenterOf(x) => format!("{}", x),
+        _ => String::new(),
+    };
+
+    chart
+        .configure_mesh()
+        .disable_mesh()
+        .x_labels(24)
+        .y_labels(7)
+        .x_label_formatter(&x_label_fmt)
+        .y_label_formatter(&y_label_fmt)
+        .x_desc(format!("hour (UTC), darkest is {:.2} users online on average", max))
+        .draw()
+        .unwrap();
+
+    let cells = occupancy.iter().enumerate().flat_map(|(day, hours)| {
+        hours.iter().enumerate().map(move |(hour, value)| {
+            let (x, y) = (hour as i32, 6 - day as i32);
+            let share = if max > 0.0 { value / max } else { 0.0 };
+
+            Rectangle::new(
+                [(SegmentValue::Exact(x), SegmentValue::Exact(y)), (SegmentValue::Exact(x + 1), SegmentValue::Exact(y + 1))],
+                util::heat_color(share).filled(),
+            )
+        })
+    });
+
+    chart.draw_series(cells).unwrap();
+}
+
+/// Horizontal bars of labeled times in seconds, drawn top to bottom in the given order
+pub fn ranking_graph<DB: DrawingBackend>(
+    canvas: &mut DrawingArea<DB, Shift>,
+    title: &str,
+    entries: Vec<(String, u64)>,
+) {
+    let max_time = entries.iter().map(|(_label, t)| *t).max().unwrap_or(0);
+    let n = entries.len().max(1) as i32;
+
+    canvas.fill(&WHITE).unwrap();
+
+    let mut chart = ChartBuilder::on(canvas)
+        .caption(title, (FontFamily::SansSerif, 50))
+        .margin_left(30)
+        .margin_right(30)
+        .x_label_area_size(40)
+        .y_label_area_size(200)
+        .build_cartesian_2d(
+            0f64..(max_time as f64 / 60.0 / 60.0).max(1.0),
+            (0i32..n - 1).into_segmented(),
+        )
+        .unwrap();
+
+    let y_label_fmt = |y: &SegmentValue<i32>| match y {
+        SegmentValue::CenterOf(y) if (0..n).contains(y) => entries
+            .get((n - 1 - *y) as usize)
+            .map(|(label, _t)| label.clone())
+            .unwrap_or_default(),
+        _ => String::new(),
+    };
+
+    chart
+        .configure_mesh()
+        .disable_y_mesh()
+        .y_labels(entries.len().max(1))
+        .y_label_formatter(&y_label_fmt)
+        .x_desc("hours")
+        .draw()
+        .unwrap();
+
+    let bars = entries.iter().enumerate().map(|(i, (_label, t))| {
+        let y = n - 1 - i as i32;
+
+        let mut bar = Rectangle::new(
+            [(0.0, SegmentValue::Exact(y)), (*t as f64 / 60.0 / 60.0, SegmentValue::Exact(y + 1))],
+            util::heat_color(0.8).filled(),
+        );
+        bar.set_margin(5, 5, 0, 0);
+        bar
+    });
+
+    chart.draw_series(bars).unwrap();
+}
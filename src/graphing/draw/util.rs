@@ -42,3 +42,11 @@ pub fn uid_to_color(uid: &str) -> RGBColor {
 
     RGBColor(r as u8, g as u8, b as u8)
 }
+
+/// Fades from white at 0.0 to a dark red at 1.0
+pub fn heat_color(share: f64) -> RGBColor {
+    let share = share.clamp(0.0, 1.0);
+    let fade = |to: u8| (255.0 - (255.0 - to as f64) * share) as u8;
+
+    RGBColor(fade(165), fade(15), fade(21))
+}
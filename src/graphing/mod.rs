@@ -1,19 +1,194 @@
+use std::collections::BTreeSet;
+use std::ops::Range;
 use std::path::Path;
 
+use chrono::{Date, Utc};
 use plotters::coord::Shift;
-use plotters::prelude::{DrawingArea, DrawingBackend};
+use plotters::prelude::{DrawingArea, DrawingBackend, IntoDrawingArea, BitMapBackend, SVGBackend};
+use serde::{Deserialize, Serialize};
+use serenity::model::id::UserId;
 pub use crate::graphing::stats::{StatResult, StatReadError};
+pub use crate::graphing::draw::BarLayout;
+use crate::format::FileFormat;
+use crate::sessions::{self, Session};
+use crate::store::StatStore;
 
 mod draw;
 mod stats;
 
-pub fn time_total_graph_from_dir<I: AsRef<Path>, DB: DrawingBackend>(input_dir: I, canvas: &mut DrawingArea<DB, Shift>) -> StatResult<()> {
-    let trans_file_path = input_dir.as_ref().join("trans.json");
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Svg,
+}
+
+impl FileFormat for ImageFormat {
+    const EXTENSIONS: &'static [(Self, &'static str)] = &[(ImageFormat::Png, "png"), (ImageFormat::Svg, "svg")];
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chart {
+    Total,
+    TimePerDay(BarLayout),
+    /// activity per weekday and hour, of the filtered users
+    Heatmap,
+    /// the pairs of users that spent the most time together, involving at least one of the filtered users
+    Pairs,
+    /// time spent in each voice channel by the filtered users
+    Channels,
+}
+
+/// How many bars ranking charts show at most
+const MAX_RANKED: usize = 15;
 
-    let dates = stats::available_datapoint_range(&input_dir)?;
-    let trans = stats::get_translations(&trans_file_path)?;
-    let st = stats::get_stats(&input_dir, dates.clone())?;
+/// Restricts which part of the stats ends up in a chart, everything available by default
+#[derive(Clone, Debug, Default)]
+pub struct GraphFilter {
+    pub dates: Option<Range<Date<Utc>>>,
+    pub users: Option<BTreeSet<UserId>>,
+    /// label users with their server nickname where they have one
+    pub prefer_nicknames: bool,
+    /// users that opted out, they never show up in any chart
+    pub hidden: BTreeSet<UserId>,
+}
+
+impl GraphFilter {
+    /// The requested dates clamped to the available ones
+    fn dates(&self, available: Range<Date<Utc>>) -> Range<Date<Utc>> {
+        match &self.dates {
+            Some(dates) => {
+                let start = dates.start.max(available.start);
+                start..dates.end.min(available.end).max(start.succ())
+            },
+            None => available,
+        }
+    }
+}
+
+pub fn time_total_graph_from_store<DB: DrawingBackend>(store: &dyn StatStore, canvas: &mut DrawingArea<DB, Shift>, filter: &GraphFilter) -> StatResult<()> {
+    let dates = filter.dates(stats::available_datapoint_range(store)?);
+    let trans = stats::get_translations(store, filter.prefer_nicknames)?;
+    let st = stats::filter_users(stats::get_stats(store, dates.clone())?, filter.users.as_ref(), &filter.hidden);
     draw::time_total_graph(canvas, st, trans, dates);
 
     Ok(())
 }
+
+pub fn time_per_day_graph_from_store<DB: DrawingBackend>(store: &dyn StatStore, canvas: &mut DrawingArea<DB, Shift>, layout: BarLayout, filter: &GraphFilter) -> StatResult<()> {
+    let dates = filter.dates(stats::available_datapoint_range(store)?);
+    let trans = stats::get_translations(store, filter.prefer_nicknames)?;
+
+    // the snapshot of the day before serves as the baseline for the first day
+    let st = stats::get_stats(store, dates.start.pred()..dates.end)?;
+    let st = stats::filter_users(stats::daily_deltas(st), filter.users.as_ref(), &filter.hidden);
+    draw::time_per_day_graph(canvas, st, trans, layout);
+
+    Ok(())
+}
+
+pub fn heatmap_graph_from_store<DB: DrawingBackend>(store: &dyn StatStore, canvas: &mut DrawingArea<DB, Shift>, filter: &GraphFilter) -> StatResult<()> {
+    let mut sessions = store.sessions()?;
+    sessions.retain(|s| !filter.hidden.contains(&s.user_id));
+
+    let span = match &filter.dates {
+        Some(dates) => dates.start.and_hms(0, 0, 0)..dates.end.and_hms(0, 0, 0).min(Utc::now()),
+        None => match stats::session_span(&sessions) {
+            Some(span) => span,
+            None => Utc::now()..Utc::now(),
+        },
+    };
+
+    let title = match &filter.users {
+        Some(users) if users.len() == 1 => {
+            let trans = stats::display_names(store, filter.prefer_nicknames)?;
+            let uid = users.iter().next().unwrap();
+
+            format!("Weekly activity of {}", trans.get(uid).cloned().unwrap_or_else(|| format!("{}", uid)))
+        },
+        _ => "Weekly activity".to_string(),
+    };
+
+    draw::heatmap_graph(canvas, stats::weekly_occupancy(&sessions, filter.users.as_ref(), span), &title);
+
+    Ok(())
+}
+
+/// All sessions of users that are not hidden within the dates of the filter
+fn filtered_sessions(store: &dyn StatStore, filter: &GraphFilter) -> StatResult<Vec<Session>> {
+    let mut sessions = store.sessions()?;
+    sessions.retain(|s| !filter.hidden.contains(&s.user_id));
+
+    Ok(match &filter.dates {
+        Some(dates) => sessions::clip(sessions, &(dates.start.and_hms(0, 0, 0)..dates.end.and_hms(0, 0, 0))),
+        None => sessions,
+    })
+}
+
+pub fn pairs_graph_from_store<DB: DrawingBackend>(store: &dyn StatStore, canvas: &mut DrawingArea<DB, Shift>, filter: &GraphFilter) -> StatResult<()> {
+    let sessions = filtered_sessions(store, filter)?;
+    let trans = stats::display_names(store, filter.prefer_nicknames)?;
+    let name = |uid: &UserId| trans.get(uid).cloned().unwrap_or_else(|| format!("{}", uid));
+
+    let mut pairs: Vec<(String, u64)> = sessions::time_together(&sessions)
+        .into_iter()
+        .filter(|((a, b), _)| filter.users.as_ref().map(|users| users.contains(a) || users.contains(b)).unwrap_or(true))
+        .map(|((a, b), secs)| (format!("{} & {}", name(&a), name(&b)), secs))
+        .collect();
+
+    pairs.sort_by_key(|(_pair, secs)| std::cmp::Reverse(*secs));
+    pairs.truncate(MAX_RANKED);
+
+    draw::ranking_graph(canvas, "Time spent together", pairs);
+
+    Ok(())
+}
+
+pub fn channels_graph_from_store<DB: DrawingBackend>(store: &dyn StatStore, canvas: &mut DrawingArea<DB, Shift>, filter: &GraphFilter) -> StatResult<()> {
+    let sessions: Vec<Session> = filtered_sessions(store, filter)?
+        .into_iter()
+        .filter(|s| filter.users.as_ref().map(|users| users.contains(&s.user_id)).unwrap_or(true))
+        .collect();
+
+    let names = store.channel_names()?;
+
+    let mut channels: Vec<(String, u64)> = sessions::time_per_channel(&sessions)
+        .into_iter()
+        .map(|(cid, secs)| (names.get(&cid).cloned().unwrap_or_else(|| format!("{}", cid)), secs))
+        .collect();
+
+    channels.sort_by_key(|(_channel, secs)| std::cmp::Reverse(*secs));
+    channels.truncate(MAX_RANKED);
+
+    draw::ranking_graph(canvas, "Time per channel", channels);
+
+    Ok(())
+}
+
+pub fn draw_chart<DB: DrawingBackend>(store: &dyn StatStore, canvas: &mut DrawingArea<DB, Shift>, chart: Chart, filter: &GraphFilter) -> StatResult<()> {
+    match chart {
+        Chart::Total => time_total_graph_from_store(store, canvas, filter)?,
+        Chart::TimePerDay(layout) => time_per_day_graph_from_store(store, canvas, layout, filter)?,
+        Chart::Heatmap => heatmap_graph_from_store(store, canvas, filter)?,
+        Chart::Pairs => pairs_graph_from_store(store, canvas, filter)?,
+        Chart::Channels => channels_graph_from_store(store, canvas, filter)?,
+    }
+
+    canvas.present()
+        .map_err(|e| StatReadError::DrawError(format!("{:?}", e)))
+}
+
+/// Draws `chart` into an image file at `path`
+pub fn render_chart<P: AsRef<Path>>(store: &dyn StatStore, path: P, format: ImageFormat, size: (u32, u32), chart: Chart, filter: &GraphFilter) -> StatResult<()> {
+    match format {
+        ImageFormat::Png => {
+            let mut drawing_area = BitMapBackend::new(path.as_ref(), size).into_drawing_area();
+            draw_chart(store, &mut drawing_area, chart, filter)
+        },
+        ImageFormat::Svg => {
+            let mut drawing_area = SVGBackend::new(path.as_ref(), size).into_drawing_area();
+            draw_chart(store, &mut drawing_area, chart, filter)
+        },
+    }
+}
@@ -1,91 +1,269 @@
-use std::collections::BTreeMap;
-use std::fs::File;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Range;
-use std::path::Path;
 
-use chrono::{Date, NaiveDate, NaiveDateTime, Utc};
+use chrono::{Date, DateTime, Datelike, Duration, Timelike, Utc};
 use thiserror::Error;
 
+use serenity::model::id::UserId;
+
+use crate::sessions::Session;
+use crate::store::{delta_secs, StatStore, StoreError};
+
 #[derive(Debug, Error)]
 pub enum StatReadError {
-    #[error("json parse error")]
-    JsonParseError(#[from] serde_json::Error),
-    #[error("io error")]
-    IOError(#[from] std::io::Error),
+    #[error("storage error: {0}")]
+    StoreError(#[from] StoreError),
+    #[error("drawing failed: {0}")]
+    DrawError(String),
 }
 
 pub type StatResult<T> = Result<T, StatReadError>;
 
-pub fn available_datapoint_range<P: AsRef<Path>>(path: P) -> std::io::Result<Range<Date<Utc>>> {
-    let mut min_date = Utc::today();
-    let mut max_date = Date::from_utc(NaiveDateTime::from_timestamp(0, 0).date(), Utc);
+pub fn available_datapoint_range(store: &dyn StatStore) -> StatResult<Range<Date<Utc>>> {
+    match store.available_dates()? {
+        Some(dates) => Ok(dates),
+        None => Ok(Utc::today()..Utc::today().succ()),
+    }
+}
 
-    for e in std::fs::read_dir(path)? {
-        let path = e?.path();
+/// The current name of every user, their nickname instead of the username if preferred and they have one
+pub fn display_names(store: &dyn StatStore, prefer_nicknames: bool) -> StatResult<BTreeMap<UserId, String>> {
+    let mut names = store.usernames()?;
 
-        if !path.is_file() {
-            continue;
-        }
+    if prefer_nicknames {
+        names.extend(store.nicknames()?);
+    }
 
-        let filestem = path.file_stem().unwrap().to_string_lossy().into_owned();
+    Ok(names)
+}
 
-        if !filestem.starts_with("stats_") {
-            continue;
-        }
+pub fn get_translations(store: &dyn StatStore, prefer_nicknames: bool) -> StatResult<BTreeMap<String, String>> {
+    Ok(display_names(store, prefer_nicknames)?
+        .into_iter()
+        .map(|(uid, name)| (format!("{}", uid), name))
+        .collect())
+}
 
-        match NaiveDate::parse_from_str(&filestem[6..], "%Y-%m-%d").map(|d| Date::from_utc(d, Utc))
-        {
-            Ok(date) => {
-                if date < min_date {
-                    min_date = date.clone();
-                }
+pub fn get_stats(
+    store: &dyn StatStore,
+    dates: Range<Date<Utc>>,
+) -> StatResult<Vec<(Date<Utc>, BTreeMap<String, u64>)>> {
+    Ok(store.totals(dates)?
+        .into_iter()
+        .map(|(date, totals)| {
+            let totals = totals.into_iter()
+                .map(|(uid, secs)| (format!("{}", uid), secs))
+                .collect();
+
+            (date, totals)
+        })
+        .collect())
+}
+
+/// Drops every user not in `users`, keeps everyone if there is no filter
+pub fn filter_users(
+    stats: Vec<(Date<Utc>, BTreeMap<String, u64>)>,
+    users: Option<&BTreeSet<UserId>>,
+    hidden: &BTreeSet<UserId>,
+) -> Vec<(Date<Utc>, BTreeMap<String, u64>)> {
+    if users.is_none() && hidden.is_empty() {
+        return stats;
+    }
 
-                if date > max_date {
-                    max_date = date;
+    let users: Option<BTreeSet<String>> = users.map(|users| users.iter().map(|uid| format!("{}", uid)).collect());
+    let hidden: BTreeSet<String> = hidden.iter().map(|uid| format!("{}", uid)).collect();
+    let keep = |user: &String| !hidden.contains(user) && users.as_ref().map(|users| users.contains(user)).unwrap_or(true);
+
+    stats.into_iter()
+        .map(|(date, stats)| (date, stats.into_iter().filter(|(user, _)| keep(user)).collect()))
+        .collect()
+}
+
+/// Turns cumulative snapshots into per-day deltas.
+///
+/// The first snapshot only serves as the baseline, as there is nothing to diff it against.
+/// If there are days without a snapshot in between, the delta is spread evenly across the missing days.
+/// A value that is lower than in the previous snapshot means the counter was reset,
+/// in that case the new value is taken as the delta.
+pub fn daily_deltas(
+    stats: Vec<(Date<Utc>, BTreeMap<String, u64>)>,
+) -> Vec<(Date<Utc>, BTreeMap<String, u64>)> {
+    let mut buf = Vec::new();
+    let mut stats = stats.into_iter();
+
+    let (mut prev_date, mut prev_stats) = match stats.next() {
+        Some(first) => first,
+        None => return buf,
+    };
+
+    for (date, cur_stats) in stats {
+        let n_days = (date - prev_date).num_days().max(1) as u64;
+
+        let mut days: Vec<(Date<Utc>, BTreeMap<String, u64>)> =
+            std::iter::successors(Some(prev_date.succ()), |d| Some(d.succ()))
+                .take(n_days as usize)
+                .map(|d| (d, BTreeMap::new()))
+                .collect();
+
+        for (user, &cur) in &cur_stats {
+            let delta = delta_secs(prev_stats.get(user).cloned().unwrap_or(0), cur);
+
+            if delta == 0 {
+                continue;
+            }
+
+            let share = delta / n_days;
+            let remainder = delta % n_days;
+
+            for (i, (_date, day)) in days.iter_mut().enumerate() {
+                let t = if i as u64 == n_days - 1 { share + remainder } else { share };
+
+                if t > 0 {
+                    day.insert(user.clone(), t);
                 }
             }
-            Err(_) => (),
         }
+
+        buf.append(&mut days);
+        prev_date = date;
+        prev_stats = cur_stats;
     }
 
-    Ok(min_date..max_date.succ())
+    buf
 }
 
-pub fn get_translations<P: AsRef<Path>>(path: P) -> StatResult<BTreeMap<String, String>> {
-    let f = File::open(path)?;
-    serde_json::from_reader(f).map_err(Into::into)
+/// Average number of users online per weekday (starting with monday) and hour of the day
+pub type Occupancy = [[f64; 24]; 7];
+
+fn hour_start(t: DateTime<Utc>) -> DateTime<Utc> {
+    t.date().and_hms(t.hour(), 0, 0)
 }
 
-fn get_stat<P: AsRef<Path>>(path: P) -> StatResult<BTreeMap<String, u64>> {
-    let f = File::open(path)?;
-    serde_json::from_reader(f).map_err(Into::into)
+/// The time from the first start to the last end of all sessions
+pub fn session_span(sessions: &[Session]) -> Option<Range<DateTime<Utc>>> {
+    let start = sessions.iter().map(|s| s.start).min()?;
+    let end = sessions.iter().map(|s| s.end).max()?;
+
+    Some(start..end)
 }
 
-pub fn get_stats<P: AsRef<Path>>(
-    path: P,
-    dates: Range<Date<Utc>>,
-) -> StatResult<Vec<(Date<Utc>, BTreeMap<String, u64>)>> {
-    let date_iter = std::iter::successors(Some(dates.start), |date| {
-        if date < &dates.end {
-            Some(date.succ())
-        } else {
-            None
+/// Computes the average occupancy of every hour of the week within `span`, in UTC.
+///
+/// Every session of a user in `users` (or of everyone, if there is no filter) is clipped to `span`
+/// and its time is summed up per hour, then divided by how often that hour of the week occurs in `span`.
+pub fn weekly_occupancy(
+    sessions: &[Session],
+    users: Option<&BTreeSet<UserId>>,
+    span: Range<DateTime<Utc>>,
+) -> Occupancy {
+    let mut online_secs = [[0i64; 24]; 7];
+    let mut occurrences = [[0u32; 24]; 7];
+
+    let sessions = sessions.iter()
+        .filter(|s| users.map(|users| users.contains(&s.user_id)).unwrap_or(true));
+
+    for session in sessions {
+        let mut t = session.start.max(span.start);
+        let end = session.end.min(span.end);
+
+        while t < end {
+            let next = (hour_start(t) + Duration::hours(1)).min(end);
+            online_secs[t.weekday().num_days_from_monday() as usize][t.hour() as usize] += (next - t).num_seconds();
+            t = next;
         }
-    });
+    }
 
-    let mut buf = Vec::new();
+    let mut t = hour_start(span.start);
 
-    for date in date_iter {
-        let filepath = path
-            .as_ref()
-            .join(format!("stats_{}.json", date.format("%Y-%m-%d")));
+    while t < span.end {
+        occurrences[t.weekday().num_days_from_monday() as usize][t.hour() as usize] += 1;
+        t += Duration::hours(1);
+    }
+
+    let mut occupancy = [[0.0; 24]; 7];
 
-        match get_stat(&filepath).map(|s| (date, s)) {
-            Ok(s) => buf.push(s),
-            Err(StatReadError::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound => (),
-            Err(e) => return Err(e),
+    for day in 0..7 {
+        for hour in 0..24 {
+            if occurrences[day][hour] > 0 {
+                occupancy[day][hour] = online_secs[day][hour] as f64 / (occurrences[day][hour] as f64 * 3600.0);
+            }
         }
     }
 
-    Ok(buf)
+    occupancy
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn day(d: u32) -> Date<Utc> {
+        Date::from_utc(NaiveDate::from_ymd_opt(2024, 1, d).unwrap(), Utc)
+    }
+
+    fn totals(entries: &[(&str, u64)]) -> BTreeMap<String, u64> {
+        entries.iter().map(|(user, secs)| (user.to_string(), *secs)).collect()
+    }
+
+    #[test]
+    fn first_snapshot_is_the_baseline() {
+        assert!(daily_deltas(Vec::new()).is_empty());
+        assert!(daily_deltas(vec![(day(1), totals(&[("a", 100)]))]).is_empty());
+
+        let deltas = daily_deltas(vec![
+            (day(1), totals(&[("a", 100)])),
+            (day(2), totals(&[("a", 160)])),
+            (day(3), totals(&[("a", 160)])),
+        ]);
+
+        assert_eq!(deltas, vec![
+            (day(2), totals(&[("a", 60)])),
+            (day(3), totals(&[])),
+        ]);
+    }
+
+    #[test]
+    fn missing_days_share_the_delta() {
+        let deltas = daily_deltas(vec![
+            (day(1), totals(&[("a", 0)])),
+            (day(4), totals(&[("a", 100)])),
+        ]);
+
+        // the remainder of the even split goes to the last day
+        assert_eq!(deltas, vec![
+            (day(2), totals(&[("a", 33)])),
+            (day(3), totals(&[("a", 33)])),
+            (day(4), totals(&[("a", 34)])),
+        ]);
+    }
+
+    #[test]
+    fn lower_total_is_a_reset() {
+        let deltas = daily_deltas(vec![
+            (day(1), totals(&[("a", 500)])),
+            (day(2), totals(&[("a", 40)])),
+            (day(3), totals(&[("a", 90)])),
+        ]);
+
+        assert_eq!(deltas, vec![
+            (day(2), totals(&[("a", 40)])),
+            (day(3), totals(&[("a", 50)])),
+        ]);
+    }
+
+    #[test]
+    fn user_appearing_later_counts_from_zero() {
+        let deltas = daily_deltas(vec![
+            (day(1), totals(&[("a", 10)])),
+            (day(2), totals(&[("a", 20), ("b", 70)])),
+            (day(3), totals(&[("a", 20), ("b", 75)])),
+        ]);
+
+        assert_eq!(deltas, vec![
+            (day(2), totals(&[("a", 10), ("b", 70)])),
+            (day(3), totals(&[("b", 5)])),
+        ]);
+    }
 }
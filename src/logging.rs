@@ -0,0 +1,191 @@
+use std::io::Write;
+
+use chrono::Utc;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number};
+
+/// modules of this crate log with this prefix, everything else comes from dependencies
+const CRATE_TARGET: &str = "stat_bot";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!("unknown log level {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `<timestamp> LEVEL target: message key=value ...`
+    #[default]
+    Human,
+    /// one json object per line, fields are top level keys next to `ts`, `level`, `target` and `msg`
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogSettings {
+    /// level of the modules of the bot, dependencies only ever log warnings and errors
+    pub level: LogLevel,
+    pub format: LogFormat,
+}
+
+impl LogSettings {
+    /// Replaces the configured values with the ones that were given on the command line
+    pub fn overridden(self, level: Option<LogLevel>, format: Option<LogFormat>) -> Self {
+        Self {
+            level: level.unwrap_or(self.level),
+            format: format.unwrap_or(self.format),
+        }
+    }
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+/// Collects the key value pairs of a record in the order they were given
+#[derive(Default)]
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            serde_json::Value::Number(n.into())
+        } else if let Some(n) = value.to_i64() {
+            serde_json::Value::Number(n.into())
+        } else if let Some(n) = value.to_f64().and_then(Number::from_f64) {
+            serde_json::Value::Number(n)
+        } else if let Some(b) = value.to_bool() {
+            serde_json::Value::Bool(b)
+        } else {
+            serde_json::Value::String(value.to_string())
+        };
+
+        self.0.push((key.to_string(), value));
+        Ok(())
+    }
+}
+
+impl Logger {
+    fn format_human(&self, record: &Record, fields: Fields) -> String {
+        let mut line = format!("<{}> {:<5} {}: {}", Utc::now().format("%Y-%m-%d_%H:%M:%S"), record.level(), record.target(), record.args());
+
+        for (key, value) in fields.0 {
+            match value {
+                serde_json::Value::String(s) => line.push_str(&format!(" {}={:?}", key, s)),
+                value => line.push_str(&format!(" {}={}", key, value)),
+            }
+        }
+
+        line
+    }
+
+    fn format_json(&self, record: &Record, fields: Fields) -> String {
+        let mut object = Map::new();
+        object.insert("ts".to_string(), Utc::now().to_rfc3339().into());
+        object.insert("level".to_string(), record.level().as_str().to_lowercase().into());
+        object.insert("target".to_string(), record.target().into());
+        object.insert("msg".to_string(), record.args().to_string().into());
+
+        for (key, value) in fields.0 {
+            object.insert(key, value);
+        }
+
+        serde_json::Value::Object(object).to_string()
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if metadata.target().starts_with(CRATE_TARGET) {
+            metadata.level() <= self.level
+        } else {
+            metadata.level() <= self.level.min(LevelFilter::Warn)
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut fields = Fields::default();
+        // a failing visitor only loses the remaining fields
+        let _ = record.key_values().visit(&mut fields);
+
+        let line = match self.format {
+            LogFormat::Human => self.format_human(record, fields),
+            LogFormat::Json => self.format_json(record, fields),
+        };
+
+        // warnings and errors go to stderr, like they did before there were levels
+        if record.level() <= Level::Warn {
+            let _ = writeln!(std::io::stderr(), "{}", line);
+        } else {
+            let _ = writeln!(std::io::stdout(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Installs the logger, only the first call has an effect
+pub fn init(settings: LogSettings) {
+    let logger = Logger { level: settings.level.filter(), format: settings.format };
+
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(settings.level.filter());
+    }
+}
@@ -5,52 +5,169 @@ extern crate tempfile;
 extern crate signal_hook;
 
 mod stats;
+mod sessions;
+mod counting;
+mod permissions;
+mod commands;
+mod args;
+mod store;
 mod stat_bot;
 mod graphing;
+mod pagination;
+mod render;
+mod export;
+mod format;
+mod slash;
+mod reply;
+mod logging;
 
 use clap::Clap;
 use serenity::client::Client;
 use std::fs::File;
 use stat_bot::Settings;
 use std::sync::{Arc, Mutex};
-use crate::stats::StatManager;
+use std::time::Duration;
+use log::{error, info};
+use signal_hook::iterator::Signals;
+use crate::stats::GuildStatManagers;
+use crate::sessions::EndReason;
+use crate::logging::{LogFormat, LogLevel, LogSettings};
 
 
 #[derive(Clap)]
 struct Opts {
+    /// required unless a subcommand is given
     #[clap(short = 's', long = "settings-file")]
-    settings_file: String,
+    settings_file: Option<String>,
+
+    /// overrides the log level of the settings file
+    #[clap(long = "log-level", possible_values = &["error", "warn", "info", "debug", "trace"])]
+    log_level: Option<LogLevel>,
+
+    /// overrides the log format of the settings file
+    #[clap(long = "log-format", possible_values = &["human", "json"])]
+    log_format: Option<LogFormat>,
+
+    #[clap(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Clap)]
+enum Command {
+    Render(render::RenderOpts),
+    Export(export::ExportOpts),
+    Import(export::ImportOpts),
 }
 
 fn main() {
     let opts: Opts = Opts::parse();
 
-    let settings: Settings = match File::open(&opts.settings_file) {
+    if opts.cmd.is_some() {
+        logging::init(LogSettings::default().overridden(opts.log_level, opts.log_format));
+    }
+
+    match opts.cmd {
+        Some(Command::Render(render_opts)) => {
+            if let Err(e) = render::render(render_opts) {
+                error!(error:% = e; "failed to render chart");
+                std::process::exit(1);
+            }
+
+            return;
+        },
+        Some(Command::Export(export_opts)) => {
+            if let Err(e) = export::export(export_opts) {
+                error!(error:% = e; "failed to export stats");
+                std::process::exit(1);
+            }
+
+            return;
+        },
+        Some(Command::Import(import_opts)) => {
+            if let Err(e) = export::import(import_opts) {
+                error!(error:% = e; "failed to import stats");
+                std::process::exit(1);
+            }
+
+            return;
+        },
+        None => (),
+    }
+
+    let settings_file = opts.settings_file
+        .expect("--settings-file is required to run the bot");
+
+    let settings: Settings = match File::open(&settings_file) {
             Ok(f) => serde_json::from_reader(f).expect("invalid json in config"),
             Err(_) => Settings::default(),
         };
 
-    let stat_man = Arc::new(Mutex::new({
-        let mut s = StatManager::new(&settings.output_dir);
-        s.read_stats()
-            .expect("failed to read stats");
+    logging::init(settings.logging.overridden(opts.log_level, opts.log_format));
+
+    std::fs::create_dir_all(&settings.output_dir)
+        .expect("failed to create output dir");
 
-        s
-    }));
+    let stat_man = Arc::new(Mutex::new(GuildStatManagers::new(&settings.output_dir, settings.storage)));
+
+    if settings.autosave_interval_secs > 0 {
+        let stat_man = stat_man.clone();
+        let interval = Duration::from_secs(settings.autosave_interval_secs);
+
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+
+            for (gid, e) in stat_man.lock().expect("lock failed in autosave").flush_stats() {
+                error!(guild = gid.0, error:? = e; "autosave failed");
+            }
+        });
+    }
 
     let tok = std::env::var("STAT_BOT_DISCORD_TOKEN")
         .expect("failed to read token from env");
 
-    let mut client = Client::new(tok, stat_bot::StatBot::new(&opts.settings_file, settings, stat_man.clone()))
+    let mut client = Client::new(tok, stat_bot::StatBot::new(&settings_file, settings, stat_man.clone()))
         .expect("failed to create discord client");
 
-    unsafe {
-        signal_hook::register(signal_hook::SIGINT, move || {
-            stat_man.lock().expect("lock failed in sighandler")
-                .flush_stats()
-                .expect("could not flush stats");
-        }).expect("failed to register signal handler");
+    {
+        let signals = Signals::new([signal_hook::SIGINT, signal_hook::SIGTERM])
+            .expect("failed to register signal handler");
+
+        let shard_manager = client.shard_manager.clone();
+        let stat_man = stat_man.clone();
+
+        std::thread::spawn(move || {
+            for sig in signals.forever() {
+                // installed copies of the old save timer still send SIGINT twice a day, so it only flushes like it used to
+                if sig == signal_hook::SIGINT {
+                    info!(signal = sig; "received signal, flushing stats, use SIGTERM to shut down");
+
+                    for (gid, e) in stat_man.lock().expect("lock failed in signal handler").flush_stats() {
+                        error!(guild = gid.0, error:? = e; "could not flush stats");
+                    }
+
+                    continue;
+                }
+
+                info!(signal = sig; "received signal, shutting down");
+                shard_manager.lock().shutdown_all();
+                break;
+            }
+        });
+    }
+
+    if let Err(e) = client.start() {
+        error!(error:? = e; "discord client failed");
     }
 
-    client.start().unwrap();
+    let mut stat_man = stat_man.lock().expect("lock failed on shutdown");
+    stat_man.end_all_sessions(EndReason::Shutdown);
+    let failed = stat_man.flush_stats();
+
+    for (gid, e) in &failed {
+        error!(guild = gid.0, error:? = e; "could not flush stats");
+    }
+
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,55 @@
+use serenity::builder::CreateEmbed;
+use serenity::model::channel::ReactionType;
+
+pub const PREV_PAGE_EMOJI: &str = "⬅️";
+pub const NEXT_PAGE_EMOJI: &str = "➡️";
+
+/// An embed whose fields are split into pages that can be flipped through with reactions
+pub struct Pages {
+    title: String,
+    fields: Vec<(String, String)>,
+    page_size: usize,
+    page: usize,
+    footer: String,
+}
+
+impl Pages {
+    pub fn new(title: String, fields: Vec<(String, String)>, page_size: usize, footer: String) -> Self {
+        Self {
+            title,
+            fields,
+            page_size: page_size.max(1),
+            page: 0,
+            footer,
+        }
+    }
+
+    pub fn n_pages(&self) -> usize {
+        self.fields.len().div_ceil(self.page_size).max(1)
+    }
+
+    /// Flips the page according to the reaction, returns whether the page changed
+    pub fn flip(&mut self, reaction: &ReactionType) -> bool {
+        match reaction {
+            ReactionType::Unicode(emoji) if emoji == PREV_PAGE_EMOJI && self.page > 0 => {
+                self.page -= 1;
+                true
+            },
+            ReactionType::Unicode(emoji) if emoji == NEXT_PAGE_EMOJI && self.page + 1 < self.n_pages() => {
+                self.page += 1;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn render<'a>(&self, e: &'a mut CreateEmbed) -> &'a mut CreateEmbed {
+        e.title(&self.title);
+
+        for (name, value) in self.fields.iter().skip(self.page * self.page_size).take(self.page_size) {
+            e.field(name, value, false);
+        }
+
+        e.footer(|f| f.text(format!("Page {}/{} | {}", self.page + 1, self.n_pages(), self.footer)))
+    }
+}
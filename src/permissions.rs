@@ -0,0 +1,105 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use serenity::model::guild::Guild;
+use serenity::model::id::{RoleId, UserId};
+
+use crate::commands::Command;
+
+/// Who may run a command, every level includes the ones below it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionLevel {
+    Everyone,
+    /// members with one of the configured roles
+    Roles,
+    /// members with the administrator permission and the owner of the guild
+    Admin,
+    /// the owner of the bot
+    Owner,
+}
+
+impl PermissionLevel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "everyone" => Some(PermissionLevel::Everyone),
+            "roles" => Some(PermissionLevel::Roles),
+            "admin" => Some(PermissionLevel::Admin),
+            "owner" => Some(PermissionLevel::Owner),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PermissionLevel::Everyone => "everyone",
+            PermissionLevel::Roles => "roles",
+            PermissionLevel::Admin => "admin",
+            PermissionLevel::Owner => "owner",
+        }
+    }
+
+    /// Describes who holds the level, to be used in denial messages
+    pub fn describe(&self) -> &'static str {
+        match self {
+            PermissionLevel::Everyone => "no special permissions",
+            PermissionLevel::Roles => "one of the privileged roles",
+            PermissionLevel::Admin => "admin permissions",
+            PermissionLevel::Owner => "being the owner of the bot",
+        }
+    }
+}
+
+/// The required permission level of each command of a guild
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Permissions {
+    /// members with one of these roles hold the `roles` level
+    pub roles: BTreeSet<RoleId>,
+    /// overrides the default level of a command or subcommand
+    pub commands: BTreeMap<String, PermissionLevel>,
+}
+
+impl Permissions {
+    /// The level `command` requires, a subcommand requires at least the level of its command
+    pub fn required(&self, command: &Command, subcommand: Option<&str>) -> PermissionLevel {
+        let command_level = self.commands.get(command.name)
+            .cloned()
+            .unwrap_or(command.permission);
+
+        let sub_level = subcommand.and_then(|sub| {
+            self.commands.get(&format!("{} {}", command.name, sub))
+                .cloned()
+                .or_else(|| command.restricted.iter().find(|(name, _)| *name == sub).map(|(_, level)| *level))
+        });
+
+        match sub_level {
+            Some(sub_level) => sub_level.max(command_level),
+            None => command_level,
+        }
+    }
+
+    /// The highest level `uid` with the roles `member_roles` holds in `guild`.
+    /// The roles are passed in, because large guilds don't have all members cached.
+    pub fn held(&self, guild: &Guild, uid: UserId, member_roles: &[RoleId], is_bot_owner: bool) -> PermissionLevel {
+        if is_bot_owner {
+            return PermissionLevel::Owner;
+        }
+
+        // the @everyone role has the id of the guild
+        let is_admin = std::iter::once(&RoleId(guild.id.0))
+            .chain(member_roles)
+            .filter_map(|role| guild.roles.get(role))
+            .any(|role| role.permissions.administrator());
+
+        if guild.owner_id == uid || is_admin {
+            return PermissionLevel::Admin;
+        }
+
+        if member_roles.iter().any(|role| self.roles.contains(role)) {
+            PermissionLevel::Roles
+        } else {
+            PermissionLevel::Everyone
+        }
+    }
+}
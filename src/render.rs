@@ -0,0 +1,94 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+use clap::Clap;
+use thiserror::Error;
+
+use crate::format::{DataOpts, FileFormat};
+use crate::graphing::{self, BarLayout, Chart, GraphFilter, ImageFormat};
+use crate::args::{parse_window, resolve_user};
+use crate::store::{self, StoreError};
+
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error("invalid argument: {0}")]
+    ArgError(String),
+    #[error("storage error: {0}")]
+    StoreError(#[from] StoreError),
+    #[error("graphing error: {0}")]
+    GraphError(#[from] graphing::StatReadError),
+}
+
+/// Draws a chart from a data directory without connecting to discord
+#[derive(Clap)]
+pub struct RenderOpts {
+    #[clap(flatten)]
+    data: DataOpts,
+
+    #[clap(short = 'c', long = "chart", default_value = "total", possible_values = &["total", "time-per-day", "time-per-day-stacked", "heatmap", "friends", "channels"])]
+    chart: String,
+
+    /// `today`, `week`, `month`, `year` or an inclusive range `YYYY-MM-DD..YYYY-MM-DD`
+    #[clap(long = "dates")]
+    dates: Option<String>,
+
+    /// only include these users, by id or name
+    #[clap(short = 'u', long = "user", multiple_occurrences = true)]
+    users: Vec<String>,
+
+    #[clap(long = "width", default_value = "1280")]
+    width: u32,
+
+    #[clap(long = "height", default_value = "720")]
+    height: u32,
+
+    /// label users with their server nickname where they have one
+    #[clap(long = "nicknames")]
+    nicknames: bool,
+
+    /// output file, the format is chosen by its extension (png or svg)
+    #[clap(short = 'o', long = "output")]
+    output: PathBuf,
+}
+
+pub fn render(opts: RenderOpts) -> Result<(), RenderError> {
+    let chart = match opts.chart.as_str() {
+        "time-per-day" => Chart::TimePerDay(BarLayout::Grouped),
+        "time-per-day-stacked" => Chart::TimePerDay(BarLayout::Stacked),
+        "heatmap" => Chart::Heatmap,
+        "friends" => Chart::Pairs,
+        "channels" => Chart::Channels,
+        _ => Chart::Total,
+    };
+
+    let format = ImageFormat::from_path(&opts.output)
+        .ok_or_else(|| RenderError::ArgError(format!("unsupported output format {:?}, use .png or .svg", opts.output)))?;
+
+    if !opts.data.data_dir.is_dir() {
+        return Err(RenderError::ArgError(format!("data directory {:?} does not exist", opts.data.data_dir)));
+    }
+
+    let store = store::open(opts.data.storage, &opts.data.data_dir)?;
+
+    let dates = match &opts.dates {
+        Some(arg) => Some(parse_window(arg).ok_or_else(|| RenderError::ArgError(format!("invalid dates {:?}", arg)))?),
+        None => None,
+    };
+
+    let users = if opts.users.is_empty() {
+        None
+    } else {
+        let trans = store.usernames()?;
+        let nicks = store.nicknames()?;
+
+        Some(opts.users.iter()
+            .map(|arg| resolve_user(arg, &trans).or_else(|| resolve_user(arg, &nicks)).ok_or_else(|| RenderError::ArgError(format!("unknown user {:?}", arg))))
+            .collect::<Result<BTreeSet<_>, _>>()?)
+    };
+
+    let filter = GraphFilter { dates, users, prefer_nicknames: opts.nicknames, hidden: store.opted_out()? };
+
+    graphing::render_chart(store.as_ref(), &opts.output, format, (opts.width, opts.height), chart, &filter)?;
+
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use std::cell::Cell;
+
+use log::warn;
+use serde_json::Value;
+use serenity::builder::{CreateEmbed, CreateMessage};
+use serenity::model::id::{ChannelId, MessageId};
+use serenity::prelude::Context;
+
+use crate::slash::{self, Interaction};
+use crate::stat_bot::BotResult;
+
+/// A message a command answers with
+#[derive(Default)]
+pub struct Reply {
+    content: Option<String>,
+    embed: Option<CreateEmbed>,
+    /// file name and contents of an attachment
+    file: Option<(String, Vec<u8>)>,
+    /// only shown to the user that used the command, prefix commands can't do that
+    ephemeral: bool,
+}
+
+impl Reply {
+    pub fn text<S: Into<String>>(content: S) -> Self {
+        Self { content: Some(content.into()), ..Default::default() }
+    }
+
+    pub fn embed<F>(f: F) -> Self
+        where F: FnOnce(&mut CreateEmbed) -> &mut CreateEmbed {
+
+        let mut embed = CreateEmbed::default();
+        f(&mut embed);
+
+        Self { embed: Some(embed), ..Default::default() }
+    }
+
+    pub fn file(filename: String, data: Vec<u8>) -> Self {
+        Self { file: Some((filename, data)), ..Default::default() }
+    }
+
+    pub fn ephemeral(self) -> Self {
+        Self { ephemeral: true, ..self }
+    }
+
+    fn build<'a, 'b>(&self, m: &'b mut CreateMessage<'a>) -> &'b mut CreateMessage<'a> {
+        if let Some(content) = &self.content {
+            m.content(content);
+        }
+
+        if let Some(embed) = &self.embed {
+            m.embed(|e| {
+                *e = embed.clone();
+                e
+            });
+        }
+
+        m
+    }
+
+    /// The message as the interaction endpoints expect it
+    fn to_json(&self) -> Value {
+        slash::message(self.content.as_deref(), self.embed.clone().map(embed_json), self.ephemeral)
+    }
+}
+
+pub fn embed_json(embed: CreateEmbed) -> Value {
+    Value::Object(serenity::utils::hashmap_to_json_map(embed.0))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Pending,
+    /// discord shows a loading state until the response is edited
+    Deferred,
+    Responded,
+}
+
+/// A message that was sent as a reply
+pub struct Sent {
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    /// application id and token of the interaction, responses to it can only be edited through its webhook
+    pub webhook: Option<(String, String)>,
+}
+
+/// Where the replies of a command go
+pub enum Responder<'a> {
+    /// messages in the channel a prefix command was typed in
+    Channel(ChannelId),
+    /// the response and follow ups of a slash command, they work without permissions in the channel
+    Interaction(&'a Interaction, Cell<State>),
+}
+
+impl<'a> Responder<'a> {
+    pub fn interaction(interaction: &'a Interaction) -> Self {
+        Responder::Interaction(interaction, Cell::new(State::Pending))
+    }
+
+    /// Shows that the bot is working on something that takes a while
+    pub fn typing(&self, ctx: &Context) {
+        // deferred interactions already show a loading state
+        if let Responder::Channel(channel_id) = self {
+            if let Err(e) = channel_id.broadcast_typing(ctx) {
+                warn!(channel = channel_id.0, error:? = e; "failed to broadcast typing");
+            }
+        }
+    }
+
+    /// Acknowledges an interaction without answering yet, the first reply replaces the loading state
+    pub fn defer(&self, ctx: &Context) -> BotResult<()> {
+        if let Responder::Interaction(interaction, state) = self {
+            if state.get() == State::Pending {
+                slash::respond(&ctx.http.token, interaction, &slash::deferred())?;
+                state.set(State::Deferred);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn send(&self, ctx: &Context, reply: Reply) -> BotResult<Option<Sent>> {
+        let (interaction, state) = match self {
+            Responder::Channel(channel_id) => {
+                let sent = match &reply.file {
+                    Some((filename, data)) => channel_id.send_files(ctx, std::iter::once((&data[..], filename.as_str())), |m| reply.build(m))?,
+                    None => channel_id.send_message(ctx, |m| reply.build(m))?,
+                };
+
+                return Ok(Some(Sent { channel_id: sent.channel_id, message_id: sent.id, webhook: None }));
+            },
+            Responder::Interaction(interaction, state) => (interaction, state),
+        };
+
+        let token = &ctx.http.token;
+        let message = reply.to_json();
+
+        let sent = match state.get() {
+            // files can't be part of the initial response, only of the edit of a deferred one
+            State::Pending if reply.file.is_some() => {
+                self.defer(ctx)?;
+                return self.send(ctx, reply);
+            },
+            State::Pending => {
+                slash::respond(token, interaction, &slash::message_response(message))?;
+                state.set(State::Responded);
+
+                // the initial response does not return the message, it is only needed to flip pages
+                slash::original(token, interaction).unwrap_or_else(|e| {
+                    warn!(interaction = interaction.id.as_str(), error:? = e; "failed to fetch interaction response");
+                    Value::Null
+                })
+            },
+            // the loading state is visible to everyone, so ephemeral replies replace it with a follow up
+            State::Deferred if reply.ephemeral => {
+                slash::delete_response(token, interaction)?;
+                state.set(State::Responded);
+                slash::follow_up(token, interaction, &message, reply.file)?
+            },
+            State::Deferred => {
+                let sent = slash::edit_original(token, interaction, &message, reply.file)?;
+                state.set(State::Responded);
+                sent
+            },
+            State::Responded => slash::follow_up(token, interaction, &message, reply.file)?,
+        };
+
+        let id = |key: &str| sent[key].as_str().and_then(|id| id.parse::<u64>().ok());
+
+        Ok(match (id("channel_id"), id("id")) {
+            (Some(channel_id), Some(message_id)) => Some(Sent {
+                channel_id: ChannelId(channel_id),
+                message_id: MessageId(message_id),
+                webhook: Some((interaction.application_id.clone(), interaction.token.clone())),
+            }),
+            _ => None,
+        })
+    }
+
+    /// Ends an interaction the command did not reply to, so that discord does not show it as failed
+    pub fn finish(&self, ctx: &Context) {
+        if let Responder::Interaction(interaction, state) = self {
+            let token = &ctx.http.token;
+
+            let finished = match state.get() {
+                State::Pending => slash::respond(token, interaction, &slash::ephemeral_message(":white_check_mark: Success: done")),
+                State::Deferred => slash::delete_response(token, interaction),
+                State::Responded => Ok(()),
+            };
+
+            if let Err(e) = finished {
+                warn!(interaction = interaction.id.as_str(), error:? = e; "failed to finish interaction");
+            }
+        }
+    }
+}
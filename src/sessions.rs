@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+
+use std::ops::Range;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, UserId};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    /// the user disconnected from voice
+    Disconnected,
+    /// the user moved to an AFK channel, or any other ignored one
+    Afk,
+    /// the user deafened themselves or was deafened
+    Deafened,
+    /// the user muted themselves or was muted, and muted users don't count
+    Muted,
+    /// not enough other humans were left in the channel
+    TooFewHumans,
+    /// bots don't count
+    Bot,
+    /// the user moved to another counted channel, so only the segment in the old one ended
+    Moved,
+    /// the user opted out of being tracked
+    OptedOut,
+    /// the bot was shut down while the user was online
+    Shutdown,
+}
+
+/// A single, finished stay of a user in a voice channel
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub user_id: UserId,
+    pub channel_id: ChannelId,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub end_reason: EndReason,
+}
+
+/// Cuts every session down to the part within `span`, dropping those entirely outside of it
+pub fn clip(sessions: Vec<Session>, span: &Range<DateTime<Utc>>) -> Vec<Session> {
+    sessions.into_iter()
+        .filter(|s| s.start < span.end && s.end > span.start)
+        .map(|s| Session { start: s.start.max(span.start), end: s.end.min(span.end), ..s })
+        .collect()
+}
+
+/// Sums up the time users spent in each channel, in seconds
+pub fn time_per_channel(sessions: &[Session]) -> BTreeMap<ChannelId, u64> {
+    let mut per_channel = BTreeMap::new();
+
+    for session in sessions {
+        *per_channel.entry(session.channel_id).or_default() += (session.end - session.start).num_seconds().max(0) as u64;
+    }
+
+    per_channel
+}
+
+/// Sums up the time each user spent in voice, in seconds
+pub fn time_per_user(sessions: &[Session]) -> BTreeMap<UserId, u64> {
+    let mut per_user = BTreeMap::new();
+
+    for session in sessions {
+        *per_user.entry(session.user_id).or_default() += (session.end - session.start).num_seconds().max(0) as u64;
+    }
+
+    per_user
+}
+
+/// Seconds two users spent in the same voice channel, keyed by the pair with the lower id first
+pub type TimeTogether = BTreeMap<(UserId, UserId), u64>;
+
+/// Sums up how long every pair of users overlapped in the same channel
+pub fn time_together(sessions: &[Session]) -> TimeTogether {
+    let mut sorted: Vec<&Session> = sessions.iter().collect();
+    sorted.sort_by_key(|s| (s.channel_id, s.start));
+
+    let mut together = TimeTogether::new();
+
+    for (i, a) in sorted.iter().enumerate() {
+        // sessions are sorted by start, so only the following ones that start before `a` ends can overlap
+        let overlapping = sorted[i + 1..]
+            .iter()
+            .take_while(|b| b.channel_id == a.channel_id && b.start < a.end)
+            .filter(|b| b.user_id != a.user_id);
+
+        for b in overlapping {
+            let secs = (a.end.min(b.end) - b.start).num_seconds();
+
+            if secs > 0 {
+                let pair = (a.user_id.min(b.user_id), a.user_id.max(b.user_id));
+                *together.entry(pair).or_default() += secs as u64;
+            }
+        }
+    }
+
+    together
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 12, minute, 0).unwrap()
+    }
+
+    fn session(user: u64, channel: u64, start: u32, end: u32) -> Session {
+        Session {
+            user_id: UserId(user),
+            channel_id: ChannelId(channel),
+            start: at(start),
+            end: at(end),
+            end_reason: EndReason::Disconnected,
+        }
+    }
+
+    fn pair(a: u64, b: u64) -> (UserId, UserId) {
+        (UserId(a), UserId(b))
+    }
+
+    #[test]
+    fn partial_overlap() {
+        let together = time_together(&[session(2, 1, 0, 10), session(1, 1, 5, 20)]);
+        assert_eq!(together.into_iter().collect::<Vec<_>>(), [(pair(1, 2), 5 * 60)]);
+    }
+
+    #[test]
+    fn containment() {
+        let together = time_together(&[session(1, 1, 0, 30), session(2, 1, 10, 20)]);
+        assert_eq!(together.into_iter().collect::<Vec<_>>(), [(pair(1, 2), 10 * 60)]);
+    }
+
+    #[test]
+    fn touching_sessions_do_not_overlap() {
+        assert!(time_together(&[session(1, 1, 0, 10), session(2, 1, 10, 20)]).is_empty());
+    }
+
+    #[test]
+    fn different_channels_do_not_overlap() {
+        assert!(time_together(&[session(1, 1, 0, 10), session(2, 2, 0, 10)]).is_empty());
+    }
+
+    #[test]
+    fn own_back_to_back_sessions() {
+        let together = time_together(&[
+            session(1, 1, 0, 10),
+            session(1, 1, 10, 20),
+            session(2, 1, 5, 15),
+        ]);
+
+        // the user is never paired with themselves, both of their sessions count towards the other user
+        assert_eq!(together.into_iter().collect::<Vec<_>>(), [(pair(1, 2), 10 * 60)]);
+    }
+
+    #[test]
+    fn three_users() {
+        let together = time_together(&[session(1, 1, 0, 30), session(2, 1, 10, 40), session(3, 1, 20, 25)]);
+
+        assert_eq!(together.into_iter().collect::<Vec<_>>(), [
+            (pair(1, 2), 20 * 60),
+            (pair(1, 3), 5 * 60),
+            (pair(2, 3), 5 * 60),
+        ]);
+    }
+
+    #[test]
+    fn clip_cuts_to_span() {
+        let sessions = vec![
+            session(1, 1, 0, 10),
+            session(1, 1, 15, 45),
+            session(1, 1, 20, 30),
+            session(1, 1, 50, 55),
+        ];
+
+        let clipped = clip(sessions, &(at(10)..at(40)));
+        let spans: Vec<(DateTime<Utc>, DateTime<Utc>)> = clipped.iter().map(|s| (s.start, s.end)).collect();
+
+        // the first session ends exactly where the span starts, so nothing of it is left
+        assert_eq!(spans, [(at(15), at(40)), (at(20), at(30))]);
+    }
+
+    #[test]
+    fn time_per_channel_sums_sessions() {
+        let per_channel = time_per_channel(&[session(1, 1, 0, 10), session(2, 1, 0, 5), session(1, 2, 0, 1)]);
+        assert_eq!(per_channel.into_iter().collect::<Vec<_>>(), [(ChannelId(1), 15 * 60), (ChannelId(2), 60)]);
+    }
+
+    #[test]
+    fn time_per_user_sums_sessions() {
+        let per_user = time_per_user(&[session(1, 1, 0, 10), session(2, 1, 0, 5), session(1, 2, 0, 1)]);
+        assert_eq!(per_user.into_iter().collect::<Vec<_>>(), [(UserId(1), 11 * 60), (UserId(2), 5 * 60)]);
+    }
+}
@@ -0,0 +1,427 @@
+use chrono::{Duration, Utc};
+use reqwest::blocking::multipart::{Form, Part};
+use reqwest::blocking::Client;
+use reqwest::Method;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use serenity::model::id::{ChannelId, GuildId, MessageId, RoleId, UserId};
+use serenity::model::user::User;
+use thiserror::Error;
+
+use crate::args::Args;
+use crate::permissions::PermissionLevel;
+use crate::commands::{self, COMMANDS, SETTINGS};
+
+const API_BASE: &str = "https://discord.com/api/v8";
+
+// interaction types
+pub const APPLICATION_COMMAND: u8 = 2;
+pub const AUTOCOMPLETE: u8 = 4;
+
+// option types
+const SUB_COMMAND: u8 = 1;
+const STRING: u8 = 3;
+const USER: u8 = 6;
+const CHANNEL: u8 = 7;
+
+const GUILD_VOICE: u8 = 2;
+
+// response types
+const CHANNEL_MESSAGE: u8 = 4;
+const DEFERRED_CHANNEL_MESSAGE: u8 = 5;
+const AUTOCOMPLETE_RESULT: u8 = 8;
+
+/// message flag that only shows the message to the user that used the command
+const EPHEMERAL: u64 = 1 << 6;
+/// discord accepts at most this many autocomplete suggestions
+const MAX_CHOICES: usize = 25;
+
+const GRAPH_SIZES: [&str; 3] = ["1280x720", "1920x1080", "800x600"];
+
+#[derive(Debug, Error)]
+pub enum SlashError {
+    #[error("http error: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("discord responded with {0}: {1}")]
+    ApiError(u16, String),
+    #[error("invalid json in response: {0}")]
+    JsonError(#[from] serde_json::Error),
+}
+
+pub type SlashResult<T> = Result<T, SlashError>;
+
+/// An `INTERACTION_CREATE` event, only the parts the bot uses
+#[derive(Deserialize)]
+pub struct Interaction {
+    pub id: String,
+    pub application_id: String,
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub token: String,
+    pub guild_id: Option<GuildId>,
+    pub channel_id: Option<ChannelId>,
+    /// the invoking member in guilds, containing the user
+    pub member: Option<Value>,
+    /// the invoking user in direct messages
+    pub user: Option<Value>,
+    #[serde(default)]
+    pub data: Value,
+}
+
+fn choice_option(name: &str, description: &str, required: bool, choices: &[&str]) -> Value {
+    let choices: Vec<Value> = choices.iter()
+        .map(|choice| json!({ "name": choice, "value": choice }))
+        .collect();
+
+    json!({ "type": STRING, "name": name, "description": description, "required": required, "choices": choices })
+}
+
+fn string_option(name: &str, description: &str, required: bool) -> Value {
+    json!({ "type": STRING, "name": name, "description": description, "required": required })
+}
+
+fn autocomplete_option(name: &str, description: &str, required: bool) -> Value {
+    json!({ "type": STRING, "name": name, "description": description, "required": required, "autocomplete": true })
+}
+
+fn user_option(name: &str, description: &str, required: bool) -> Value {
+    json!({ "type": USER, "name": name, "description": description, "required": required })
+}
+
+fn voice_channel_option(name: &str, description: &str, required: bool) -> Value {
+    json!({ "type": CHANNEL, "name": name, "description": description, "required": required, "channel_types": [GUILD_VOICE] })
+}
+
+fn sub_command(name: &str, description: &str, options: Vec<Value>) -> Value {
+    json!({ "type": SUB_COMMAND, "name": name, "description": description, "options": options })
+}
+
+/// All commands of the bot as application commands.
+///
+/// The order of the options is the order of the arguments of the prefix commands they are translated into,
+/// except for the options named after flags.
+pub fn definitions() -> Value {
+    let settings: Vec<&str> = SETTINGS.iter().map(|setting| setting.name).collect();
+    let commands: Vec<&str> = COMMANDS.iter().map(|command| command.name).collect();
+    let window = || autocomplete_option("window", "today, week, month, year or YYYY-MM-DD..YYYY-MM-DD", false);
+
+    json!([
+        {
+            "name": "stats",
+            "description": "Time spent in voice channels",
+            "options": [
+                sub_command("user", "Stats of a single user", vec![user_option("user", "whose stats to show", true)]),
+                sub_command("top", "The leaderboard", vec![window()]),
+                sub_command("friends", "Who spent the most time with a user", vec![user_option("user", "defaults to you", false)]),
+                sub_command("channels", "Time spent in each voice channel", vec![window()]),
+                sub_command("channel", "Who spent the most time in a voice channel", vec![
+                    voice_channel_option("channel", "which channel", true),
+                    window(),
+                ]),
+                sub_command("graph", "Draws a chart", vec![
+                    choice_option("chart", "which chart to draw", true, &["total", "time-per-day", "time-per-day stacked", "heatmap", "friends", "channels"]),
+                    user_option("user", "only for heatmap, friends and channels", false),
+                    choice_option("format", "defaults to the server setting", false, &["png", "svg"]),
+                    autocomplete_option("size", "<width>x<height>, defaults to the server setting", false),
+                    string_option("from", "first day to draw, YYYY-MM-DD", false),
+                    string_option("to", "last day to draw, YYYY-MM-DD, defaults to today", false),
+                ]),
+            ],
+        },
+        {
+            "name": "settings",
+            "description": "Shows or changes the settings of this server",
+            "options": [
+                choice_option("option", "which setting to change", false, &settings),
+                autocomplete_option("value", "the new value", false),
+            ],
+        },
+        {
+            "name": "force-username-update",
+            "description": "Fetches the current names of all users",
+        },
+        {
+            "name": "whois",
+            "description": "Every name a user was seen with",
+            "options": [user_option("user", "whose names to show", true)],
+        },
+        {
+            "name": "export",
+            "description": "Exports stats as a file",
+            "options": [
+                choice_option("scope", "defaults to your own stats", false, &["me", "server"]),
+                choice_option("format", "defaults to csv", false, &["csv", "json"]),
+            ],
+        },
+        {
+            "name": "privacy",
+            "description": "Opt out of being tracked or delete your stats",
+            "options": [choice_option("action", "what to do", true, &["optout", "optin", "forget", "list"])],
+        },
+        {
+            "name": "help",
+            "description": "Lists all commands or explains one of them",
+            "options": [choice_option("command", "which command to explain", false, &commands)],
+        },
+    ])
+}
+
+fn find_by_name<'a>(values: &'a Value, name: &str) -> Option<&'a Value> {
+    values.as_array()?
+        .iter()
+        .find(|value| value["name"] == name)
+}
+
+/// The arguments of an invoked command, starting with its name and the name of the subcommand.
+///
+/// Option values are split on whitespace and taken as they are, quotes and leading dashes mean nothing in them.
+/// Options named after a flag of the command become that flag.
+pub fn args(data: &Value) -> Option<Args> {
+    let name = data["name"].as_str()?;
+    let flags = commands::find(name)?.flags;
+    let definitions = definitions();
+    let mut definition = find_by_name(&definitions, name)?;
+    let mut options = &data["options"];
+    let mut words = vec![name.to_string()];
+    let mut given_flags = Vec::new();
+
+    if let Some(sub) = options.get(0).filter(|option| option["type"] == SUB_COMMAND) {
+        let sub_name = sub["name"].as_str()?;
+        definition = find_by_name(&definition["options"], sub_name)?;
+        options = &sub["options"];
+        words.push(sub_name.to_string());
+    }
+
+    for option in definition["options"].as_array().into_iter().flatten() {
+        let option_name = option["name"].as_str()?;
+
+        if let Some(given) = find_by_name(options, option_name) {
+            let value = match &given["value"] {
+                Value::String(s) => s.clone(),
+                value => value.to_string(),
+            };
+
+            if flags.contains(&option_name) {
+                given_flags.push((option_name.to_string(), Some(value)));
+            } else {
+                words.extend(value.split_whitespace().map(str::to_string));
+            }
+        }
+    }
+
+    Some(Args::new(words, given_flags))
+}
+
+/// The user that invoked a command, with their roles if it was invoked in a guild
+pub fn author(interaction: &Interaction) -> Option<(User, Option<Vec<RoleId>>)> {
+    let user = interaction.member.as_ref()
+        .map(|member| &member["user"])
+        .or(interaction.user.as_ref())?;
+
+    let roles = interaction.member.as_ref()
+        .and_then(|member| serde_json::from_value(member["roles"].clone()).ok());
+
+    Some((serde_json::from_value(user.clone()).ok()?, roles))
+}
+
+/// Suggestions for the option that is currently being typed
+pub fn autocomplete(data: &Value) -> Vec<String> {
+    let mut options = &data["options"];
+
+    if let Some(sub) = options.get(0).filter(|option| option["type"] == SUB_COMMAND) {
+        options = &sub["options"];
+    }
+
+    let focused = match options.as_array().and_then(|options| options.iter().find(|option| option["focused"] == true)) {
+        Some(focused) => focused,
+        None => return Vec::new(),
+    };
+
+    let typed = focused["value"].as_str().unwrap_or("");
+
+    let suggestions: Vec<String> = match focused["name"].as_str() {
+        Some("window") => {
+            let today = Utc::now().date();
+            let last_month = format!("{}..{}", (today - Duration::days(30)).format("%Y-%m-%d"), today.format("%Y-%m-%d"));
+
+            vec!["today".to_string(), "week".to_string(), "month".to_string(), "year".to_string(), last_month]
+        },
+        Some("size") => GRAPH_SIZES.iter().map(|s| s.to_string()).collect(),
+        Some("value") => match find_by_name(options, "option").and_then(|option| option["value"].as_str()) {
+            Some("page-size") => vec!["5".to_string(), "10".to_string(), "25".to_string()],
+            Some("graph-format") => vec!["png".to_string(), "svg".to_string()],
+            Some("graph-size") => GRAPH_SIZES.iter().map(|s| s.to_string()).collect(),
+            Some("display-name") => vec!["username".to_string(), "nickname".to_string()],
+            Some("permission") => COMMANDS.iter()
+                .map(|command| command.name)
+                .flat_map(|command| {
+                    [PermissionLevel::Everyone, PermissionLevel::Roles, PermissionLevel::Admin]
+                        .iter()
+                        .map(move |level| format!("{} {}", command, level.name()))
+                })
+                .collect(),
+            Some("roles") => vec!["none".to_string()],
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    };
+
+    suggestions.into_iter()
+        .filter(|suggestion| suggestion.starts_with(typed))
+        .take(MAX_CHOICES)
+        .collect()
+}
+
+/// Commands that upload a file, rendering it may take longer than discord waits for the response
+pub fn takes_long(data: &Value) -> bool {
+    match data["name"].as_str() {
+        Some("export") => true,
+        Some("stats") => data["options"][0]["name"] == "graph",
+        _ => false,
+    }
+}
+
+/// Shows a loading state until the response is deleted or edited
+pub fn deferred() -> Value {
+    json!({ "type": DEFERRED_CHANNEL_MESSAGE })
+}
+
+/// A message object as the interaction endpoints expect it, `embed` is a serialized serenity embed
+pub fn message(content: Option<&str>, embed: Option<Value>, ephemeral: bool) -> Value {
+    let embeds: Vec<Value> = embed.into_iter().collect();
+    json!({ "content": content, "embeds": embeds, "flags": if ephemeral { EPHEMERAL } else { 0 } })
+}
+
+/// Answers with `message` right away
+pub fn message_response(message: Value) -> Value {
+    json!({ "type": CHANNEL_MESSAGE, "data": message })
+}
+
+/// A message only the invoking user can see
+pub fn ephemeral_message(content: &str) -> Value {
+    message_response(message(Some(content), None, true))
+}
+
+pub fn choices(suggestions: Vec<String>) -> Value {
+    let choices: Vec<Value> = suggestions.into_iter()
+        .map(|suggestion| json!({ "name": suggestion, "value": suggestion }))
+        .collect();
+
+    json!({ "type": AUTOCOMPLETE_RESULT, "data": { "choices": choices } })
+}
+
+/// Sends a request to the discord api and returns the response body, `null` if there is none.
+/// `token` already has to contain the `Bot ` prefix, a `file` is uploaded next to the json body.
+fn request(token: &str, method: Method, path: &str, body: Option<&Value>, file: Option<(String, Vec<u8>)>) -> SlashResult<Value> {
+    let mut req = Client::new()
+        .request(method, &format!("{}{}", API_BASE, path))
+        .header("Authorization", token);
+
+    match (body, file) {
+        (body, Some((filename, data))) => {
+            let payload = body.map(Value::to_string).unwrap_or_else(|| "{}".to_string());
+            let form = Form::new()
+                .text("payload_json", payload)
+                .part("file", Part::bytes(data).file_name(filename));
+
+            req = req.multipart(form);
+        },
+        (Some(body), None) => req = req.json(body),
+        (None, None) => (),
+    }
+
+    let res = req.send()?;
+    let status = res.status();
+    let text = res.text().unwrap_or_default();
+
+    if !status.is_success() {
+        Err(SlashError::ApiError(status.as_u16(), text))
+    } else if text.is_empty() {
+        Ok(Value::Null)
+    } else {
+        Ok(serde_json::from_str(&text)?)
+    }
+}
+
+/// Replaces the commands of the bot in the guild with the current definitions, guild commands are available immediately
+pub fn register_commands(token: &str, app_id: UserId, gid: GuildId) -> SlashResult<()> {
+    request(token, Method::PUT, &format!("/applications/{}/guilds/{}/commands", app_id, gid), Some(&definitions()), None)?;
+    Ok(())
+}
+
+/// The initial response, it has to be sent within 3 seconds
+pub fn respond(token: &str, interaction: &Interaction, response: &Value) -> SlashResult<()> {
+    request(token, Method::POST, &format!("/interactions/{}/{}/callback", interaction.id, interaction.token), Some(response), None)?;
+    Ok(())
+}
+
+/// The message the initial response created
+pub fn original(token: &str, interaction: &Interaction) -> SlashResult<Value> {
+    request(token, Method::GET, &format!("/webhooks/{}/{}/messages/@original", interaction.application_id, interaction.token), None, None)
+}
+
+/// Replaces the loading state of a deferred response or the message of the initial response
+pub fn edit_original(token: &str, interaction: &Interaction, message: &Value, file: Option<(String, Vec<u8>)>) -> SlashResult<Value> {
+    request(token, Method::PATCH, &format!("/webhooks/{}/{}/messages/@original", interaction.application_id, interaction.token), Some(message), file)
+}
+
+/// Sends another message after the initial response
+pub fn follow_up(token: &str, interaction: &Interaction, message: &Value, file: Option<(String, Vec<u8>)>) -> SlashResult<Value> {
+    request(token, Method::POST, &format!("/webhooks/{}/{}?wait=true", interaction.application_id, interaction.token), Some(message), file)
+}
+
+/// Edits a message sent in response to an interaction, possible for as long as its token is valid
+pub fn edit_message(token: &str, application_id: &str, interaction_token: &str, message_id: MessageId, message: &Value) -> SlashResult<()> {
+    request(token, Method::PATCH, &format!("/webhooks/{}/{}/messages/{}", application_id, interaction_token, message_id), Some(message), None)?;
+    Ok(())
+}
+
+pub fn delete_response(token: &str, interaction: &Interaction) -> SlashResult<()> {
+    request(token, Method::DELETE, &format!("/webhooks/{}/{}/messages/@original", interaction.application_id, interaction.token), None, None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words_and_flags(data: Value) -> (Vec<String>, Option<String>) {
+        let args = args(&data).unwrap();
+        let words = (0..args.len()).filter_map(|i| args.get(i)).map(str::to_string).collect();
+
+        (words, args.flag("from").unwrap())
+    }
+
+    #[test]
+    fn option_values_are_taken_as_they_are() {
+        let (words, _) = words_and_flags(json!({
+            "name": "settings",
+            "options": [{ "name": "option", "value": "prefix" }, { "name": "value", "value": "\"!" }],
+        }));
+
+        assert_eq!(words, ["settings", "prefix", "\"!"]);
+
+        let (words, from) = words_and_flags(json!({
+            "name": "stats",
+            "options": [{ "type": SUB_COMMAND, "name": "top", "options": [{ "name": "window", "value": "--x" }] }],
+        }));
+
+        assert_eq!(words, ["stats", "top", "--x"]);
+        assert_eq!(from, None);
+    }
+
+    #[test]
+    fn options_named_after_flags_become_flags() {
+        let (words, from) = words_and_flags(json!({
+            "name": "stats",
+            "options": [{ "type": SUB_COMMAND, "name": "graph", "options": [
+                { "name": "from", "value": "2024-01-01" },
+                { "name": "chart", "value": "time-per-day stacked" },
+                { "name": "format", "value": "svg" },
+            ] }],
+        }));
+
+        assert_eq!(words, ["stats", "graph", "time-per-day", "stacked", "svg"]);
+        assert_eq!(from.as_deref(), Some("2024-01-01"));
+    }
+}
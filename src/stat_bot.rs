@@ -1,28 +1,117 @@
-use serenity::model::channel::{Message, GuildChannel, ChannelType};
+use serenity::builder::CreateEmbed;
+use serenity::model::channel::{Message, Reaction, ReactionType};
 use serenity::model::gateway::Ready;
-use serenity::model::id::{GuildId, ChannelId, UserId};
+use serenity::model::guild::Guild;
+use serenity::model::id::{GuildId, ChannelId, MessageId, RoleId, UserId};
+use serenity::model::user::User;
 use serenity::model::voice::VoiceState;
 use serenity::prelude::{EventHandler, Context};
 
 use crate::stats::*;
+use crate::sessions::{EndReason, Session};
+use crate::store::{self, NameKind, StorageBackend, StoreError};
+use crate::pagination::{self, Pages};
+use crate::counting::CountingRules;
+use crate::permissions::{PermissionLevel, Permissions};
+use crate::graphing::{BarLayout, Chart, GraphFilter, ImageFormat, StatReadError};
+use crate::export::{self, ExportFormat, ExportError};
+use crate::format::FileFormat;
+use crate::slash::{self, SlashError};
+use crate::reply::{self, Reply, Responder};
+use crate::commands::{self, Handler, SETTINGS};
+use crate::args::{parse_channel, parse_role, parse_window, resolve_user, ArgError, Args};
+use crate::logging::LogSettings;
 
-use std::collections::{HashMap, BTreeMap};
-use std::fs::File;
+use std::collections::{HashMap, BTreeMap, BTreeSet};
 use std::sync::{Mutex, Arc};
-use chrono::Utc;
+use chrono::{Date, NaiveDate, Utc};
 use std::time::Duration;
 use std::path::{PathBuf, Path};
+use std::ops::Range;
 
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use plotters::prelude::{IntoDrawingArea, BitMapBackend};
+use thiserror::Error;
 
 pub const DEFAULT_PREFIX: &str = ">>";
-const SETTINGS_CHOICES: [&str; 1] = ["prefix"];
-const SETTINGS_CHOICES_DESCR: [&str; 1] = [":exclamation: prefix"];
+const DEFAULT_AUTOSAVE_INTERVAL_SECS: u64 = 15 * 60;
+const DEFAULT_PAGE_SIZE: usize = 10;
+const MAX_PAGE_SIZE: usize = 25;
+/// how many paginated messages are remembered for reaction navigation
+const MAX_PAGINATED_MESSAGES: usize = 100;
+const DEFAULT_GRAPH_SIZE: (u32, u32) = (1280, 720);
+const MIN_GRAPH_SIZE: u32 = 200;
+const MAX_GRAPH_SIZE: u32 = 4096;
 
-enum UserState {
-    Online,
-    Offline
+/// Everything that can go wrong while running a command, the message is what the user is shown
+#[derive(Debug, Error)]
+pub enum BotError {
+    /// the command was used wrong, the message explains how to use it instead
+    #[error("{0}")]
+    Usage(String),
+    #[error("{0}")]
+    ArgError(#[from] ArgError),
+    #[error("failed to read stats")]
+    StatError(#[from] StatParseError),
+    #[error("failed to draw graph")]
+    GraphError(#[from] StatReadError),
+    #[error("failed to export stats")]
+    ExportError(#[from] ExportError),
+    #[error("failed to save settings")]
+    SettingsError(#[from] StoreError),
+    #[error("failed to write file")]
+    IOError(#[from] std::io::Error),
+    #[error("discord request failed")]
+    DiscordError(#[from] serenity::Error),
+    #[error("discord request failed")]
+    SlashError(#[from] SlashError),
+}
+
+impl BotError {
+    /// Mistakes of the user are not worth logging
+    fn is_internal(&self) -> bool {
+        !matches!(self, BotError::Usage(_) | BotError::ArgError(_))
+    }
+}
+
+pub type BotResult<T> = Result<T, BotError>;
+
+fn usage<T>(mes: impl Into<String>) -> BotResult<T> {
+    Err(BotError::Usage(mes.into()))
+}
+
+fn reply_err(ctx: &Context, out: &Responder, mes: &str) -> BotResult<()> {
+    out.send(ctx, Reply::text(format!(":x: Error: {}", mes)).ephemeral())?;
+    Ok(())
+}
+
+fn reply_sucess(ctx: &Context, out: &Responder, mes: &str) -> BotResult<()> {
+    out.send(ctx, Reply::text(format!(":white_check_mark: Success: {}", mes)))?;
+    Ok(())
+}
+
+/// Who used a command, taken from the message or the interaction
+struct Author {
+    user: User,
+    /// the roles of the author, if they came with the message or interaction
+    roles: Option<Vec<RoleId>>,
+    channel_id: ChannelId,
+}
+
+impl Author {
+    fn of_message(msg: &Message) -> Self {
+        Self {
+            user: msg.author.clone(),
+            roles: msg.member.as_ref().map(|member| member.roles.clone()),
+            channel_id: msg.channel_id,
+        }
+    }
+}
+
+enum VoiceEvent {
+    Joined(ChannelId),
+    /// the session that ended by leaving
+    Left(Session),
 }
 
 fn seconds_to_discord_formatted(s_total: u64) -> String {
@@ -34,59 +123,429 @@ fn seconds_to_discord_formatted(s_total: u64) -> String {
     format!("*{}* ***D***, *{}* ***H***, *{}* ***M***, *{}* ***S***", d, h, m, s)
 }
 
-fn log_user_state_change(uid: &UserId, username: Option<&String>, state: UserState) {
+/// Resolves a user like `resolve_user`, also accepting their server nickname.
+/// Users that opted out can not be looked up.
+fn resolve_member(arg: &str, st: &StatManager) -> Option<UserId> {
+    resolve_user(arg, &st.generate_translations())
+        .or_else(|| resolve_user(arg, st.nicknames()))
+        .filter(|uid| !st.is_hidden(uid))
+}
+
+/// Parses `<width>x<height>` of a chart in pixels
+fn parse_graph_size(arg: &str) -> Option<(u32, u32)> {
+    let mut split = arg.splitn(2, 'x');
+    let width: u32 = split.next()?.parse().ok()?;
+    let height: u32 = split.next()?.parse().ok()?;
+
+    let valid = MIN_GRAPH_SIZE..=MAX_GRAPH_SIZE;
+
+    if valid.contains(&width) && valid.contains(&height) {
+        Some((width, height))
+    } else {
+        None
+    }
+}
+
+/// The dates given with `--from` and `--to`, both inclusive, `--to` defaults to today
+fn flag_window(args: &Args) -> BotResult<Option<Range<Date<Utc>>>> {
+    let from: Option<NaiveDate> = args.flag("from")?;
+    let to: Option<NaiveDate> = args.flag("to")?;
 
-    let now = Utc::now().format("%Y-%m-%d_%H:%M:%S");
+    match (from, to) {
+        (None, None) => Ok(None),
+        (None, Some(_)) => usage("`--to` requires `--from`"),
+        (Some(from), to) => {
+            let from = Date::from_utc(from, Utc);
+            let to = to.map(|to| Date::from_utc(to, Utc)).unwrap_or_else(Utc::today);
 
-    match username {
-        Some(name) => match state {
-            UserState::Online  => println!("<{now}> User joined: {name}", now=now, name=name),
-            UserState::Offline => println!("<{now}> User left: {name}", now=now, name=name),
+            if from <= to {
+                Ok(Some(from..to.succ()))
+            } else {
+                usage("`--from` must not be after `--to`")
+            }
         },
-        None => match state {
-            UserState::Online => {
-                println!("<{now}> User joined: {uid}", now=now, uid=uid);
-                eprintln!("  ^- E: failed to receive username for: {:?}", uid);
-            },
-            UserState::Offline => {
-                println!("<{now}> User left: {uid}", now=now, uid=uid);
-                eprintln!("  ^- E: failed to receive username for: {:?}", uid);
-            },
+    }
+}
+
+/// The window of a leaderboard, given as the word at `index` or with `--from` and `--to`, the current week by default
+fn window_from_args(args: &Args, index: usize) -> BotResult<Range<Date<Utc>>> {
+    let window = match args.word_with(index, "window", parse_window) {
+        Ok(window) => window,
+        Err(e) => return usage(format!("{}, expected one of 'today', 'week', 'month', 'year' or '<YYYY-MM-DD>..<YYYY-MM-DD>'", e)),
+    };
+
+    match (window, flag_window(args)?) {
+        (Some(_), Some(_)) => usage("expected either a window or `--from` and `--to`, not both"),
+        (Some(window), None) | (None, Some(window)) => Ok(window),
+        (None, None) => Ok(parse_window("week").expect("week is a window")),
+    }
+}
+
+fn rank_change(rank: usize, prev_rank: Option<usize>) -> String {
+    match prev_rank {
+        Some(prev) if prev > rank => format!(":arrow_up: {}", prev - rank),
+        Some(prev) if prev < rank => format!(":arrow_down: {}", rank - prev),
+        Some(_) => ":left_right_arrow:".to_string(),
+        None => ":new:".to_string(),
+    }
+}
+
+fn position_footer(position: Option<usize>) -> String {
+    match position {
+        Some(pos) => format!("Your position: #{}", pos + 1),
+        None => "You are not ranked".to_string(),
+    }
+}
+
+fn log_voice_event(gid: GuildId, uid: UserId, username: Option<&String>, event: VoiceEvent) {
+    match event {
+        VoiceEvent::Joined(channel_id) => {
+            info!(guild = gid.0, user = uid.0, channel = channel_id.0, username = username; "user joined voice");
+        },
+        VoiceEvent::Left(session) => {
+            let duration_secs = (session.end - session.start).num_seconds();
+            info!(guild = gid.0, user = uid.0, channel = session.channel_id.0, username = username, duration_secs = duration_secs, reason:? = session.end_reason; "user left voice");
         },
     }
+
+    if username.is_none() {
+        warn!(guild = gid.0, user = uid.0; "failed to receive username");
+    }
+}
+
+
+fn default_page_size() -> usize {
+    DEFAULT_PAGE_SIZE
 }
 
+fn default_graph_size() -> (u32, u32) {
+    DEFAULT_GRAPH_SIZE
+}
+
+/// Which name users are shown with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayName {
+    #[default]
+    Username,
+    /// the server nickname, falling back to the username for users without one
+    Nickname,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GuildSettings {
+    pub prefix: String,
+    /// number of users per leaderboard page
+    #[serde(default = "default_page_size")]
+    pub page_size: usize,
+    #[serde(default)]
+    pub counting: CountingRules,
+    #[serde(default)]
+    pub graph_format: ImageFormat,
+    /// width and height of charts in pixels
+    #[serde(default = "default_graph_size")]
+    pub graph_size: (u32, u32),
+    #[serde(default)]
+    pub display_name: DisplayName,
+    /// users that do not want to be tracked, they are neither counted nor shown anywhere
+    #[serde(default)]
+    pub opted_out: BTreeSet<UserId>,
+    #[serde(default)]
+    pub permissions: Permissions,
+}
+
+impl Default for GuildSettings {
+    fn default() -> Self {
+        Self{ prefix: DEFAULT_PREFIX.to_string(), page_size: DEFAULT_PAGE_SIZE, counting: Default::default(), graph_format: Default::default(), graph_size: DEFAULT_GRAPH_SIZE, display_name: Default::default(), opted_out: Default::default(), permissions: Default::default() }
+    }
+}
+
+
+fn default_autosave_interval_secs() -> u64 {
+    DEFAULT_AUTOSAVE_INTERVAL_SECS
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Settings {
+    /// prefix used in guilds that did not configure their own
     pub prefix: String,
     pub output_dir: PathBuf,
+    #[serde(default)]
+    pub storage: StorageBackend,
+    /// how often the stats are written to disk, 0 disables autosaving
+    #[serde(default = "default_autosave_interval_secs")]
+    pub autosave_interval_secs: u64,
+    #[serde(default)]
+    pub guilds: BTreeMap<GuildId, GuildSettings>,
+    /// users with owner permissions in every guild, the owner of the bot application always has them
+    #[serde(default)]
+    pub owners: BTreeSet<UserId>,
+    #[serde(default)]
+    pub logging: LogSettings,
+}
+
+impl Settings {
+    pub fn guild(&self, gid: GuildId) -> GuildSettings {
+        self.guilds.get(&gid)
+            .cloned()
+            .unwrap_or_else(|| GuildSettings{ prefix: self.prefix.clone(), ..Default::default() })
+    }
+
+    pub fn guild_mut(&mut self, gid: GuildId) -> &mut GuildSettings {
+        let default = GuildSettings{ prefix: self.prefix.clone(), ..Default::default() };
+        self.guilds.entry(gid).or_insert(default)
+    }
 }
 
 impl Default for Settings {
     fn default() -> Self {
-        Self{ prefix: DEFAULT_PREFIX.to_string(), output_dir: PathBuf::from("./data") }
+        Self{ prefix: DEFAULT_PREFIX.to_string(), output_dir: PathBuf::from("./data"), storage: Default::default(), autosave_interval_secs: DEFAULT_AUTOSAVE_INTERVAL_SECS, guilds: Default::default(), owners: Default::default(), logging: Default::default() }
     }
 }
 
+/// A paginated reply that can still be flipped through
+struct PagedMessage {
+    pages: Pages,
+    /// application id and token of the interaction the message answered, if it was one
+    webhook: Option<(String, String)>,
+}
 
 pub struct StatBot {
     settings: Mutex<Settings>,
     settings_path: PathBuf,
-    stat_man: Arc<Mutex<StatManager>>,
+    stat_man: Arc<Mutex<GuildStatManagers>>,
+    pages: Mutex<BTreeMap<MessageId, PagedMessage>>,
+    /// id of the bot application, known once the bot is ready
+    app_id: Mutex<Option<UserId>>,
+    /// owner of the bot application, known once the bot is ready
+    app_owner: Mutex<Option<UserId>>,
 }
 
 impl StatBot {
-    pub fn new<P: AsRef<Path>>(settings_path: P, settings: Settings, stat_man: Arc<Mutex<StatManager>>) -> Self {
+    pub fn new<P: AsRef<Path>>(settings_path: P, settings: Settings, stat_man: Arc<Mutex<GuildStatManagers>>) -> Self {
         Self {
             settings: Mutex::new(settings),
             settings_path: settings_path.as_ref().to_path_buf(),
             stat_man,
+            pages: Default::default(),
+            app_id: Default::default(),
+            app_owner: Default::default(),
+        }
+    }
+
+    /// The roles of `author`, from the message or interaction itself, the cache or discord, in that order
+    fn member_roles(&self, ctx: &Context, gid: GuildId, author: &Author) -> Vec<RoleId> {
+        let uid = author.user.id;
+
+        if let Some(roles) = &author.roles {
+            return roles.clone();
+        }
+
+        let cached = gid.to_guild_cached(ctx)
+            .and_then(|guild| guild.read().members.get(&uid).map(|member| member.roles.clone()));
+
+        if let Some(roles) = cached {
+            return roles;
+        }
+
+        match gid.member(ctx, uid) {
+            Ok(member) => member.roles,
+            Err(e) => {
+                warn!(guild = gid.0, user = uid.0, error:? = e; "failed to fetch member, assuming no roles");
+                Vec::new()
+            },
+        }
+    }
+
+    /// The highest permission level `author` holds in the guild
+    fn permission_level(&self, guild_settings: &GuildSettings, ctx: &Context, gid: GuildId, author: &Author) -> PermissionLevel {
+        let uid = author.user.id;
+        let is_bot_owner = self.settings.lock().unwrap().owners.contains(&uid) || *self.app_owner.lock().unwrap() == Some(uid);
+
+        let roles = self.member_roles(ctx, gid, author);
+
+        match gid.to_guild_cached(ctx) {
+            Some(guild) => guild_settings.permissions.held(&guild.read(), uid, &roles, is_bot_owner),
+            None if is_bot_owner => PermissionLevel::Owner,
+            None => PermissionLevel::Everyone,
+        }
+    }
+
+    /// Sends the first page and remembers the message for reaction navigation if there is more than one
+    fn send_pages(&self, ctx: &Context, out: &Responder, pages: Pages) -> BotResult<()> {
+        let sent = out.send(ctx, Reply::embed(|e| pages.render(e)))?;
+
+        if let (Some(sent), true) = (sent, pages.n_pages() > 1) {
+            for emoji in &[pagination::PREV_PAGE_EMOJI, pagination::NEXT_PAGE_EMOJI] {
+                if let Err(e) = sent.channel_id.create_reaction(ctx, sent.message_id, ReactionType::Unicode(emoji.to_string())) {
+                    warn!(channel = sent.channel_id.0, error:? = e; "failed to add page reaction");
+                }
+            }
+
+            let mut all_pages = self.pages.lock().unwrap();
+            all_pages.insert(sent.message_id, PagedMessage { pages, webhook: sent.webhook });
+
+            if all_pages.len() > MAX_PAGINATED_MESSAGES {
+                let oldest = *all_pages.keys().next().unwrap();
+                all_pages.remove(&oldest);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn save_settings(&self, settings: &Settings) -> BotResult<()> {
+        // the settings hold what can't be recreated, like the opted out users, so a crash must not truncate them
+        store::write_json_atomically(&self.settings_path, settings)?;
+        Ok(())
+    }
+
+    /// Applies the counting rules to everyone in a voice channel of the guild and updates who is online.
+    /// Does nothing if the guild is not cached yet.
+    fn update_voice_states(&self, ctx: &Context, gid: GuildId) {
+        let guild = match gid.to_guild_cached(ctx) {
+            Some(guild) => guild,
+            None => return,
+        };
+
+        let guild = guild.read();
+        let GuildSettings { counting: rules, opted_out, .. } = self.settings.lock().unwrap().guild(gid);
+
+        // this runs on every voice event, so users are only looked up in the cache and never fetched
+        let users: HashMap<UserId, (Option<String>, bool)> = guild.voice_states.keys()
+            .map(|uid| {
+                let user = match guild.members.get(uid) {
+                    Some(member) => Some(member.user.read().clone()),
+                    None => uid.to_user_cached(ctx).map(|user| user.read().clone()),
+                };
+
+                match user {
+                    Some(user) => (*uid, (Some(user.name), user.bot)),
+                    // uncached users keep their last known name and are counted as humans
+                    None => (*uid, (None, false)),
+                }
+            })
+            .collect();
+
+        // only known for members in the cache
+        let nicknames: HashMap<UserId, Option<String>> = guild.voice_states.keys()
+            .filter(|uid| !opted_out.contains(uid))
+            .filter_map(|uid| guild.members.get(uid).map(|member| (*uid, member.nick.clone())))
+            .collect();
+
+        let mut humans_per_channel: HashMap<ChannelId, usize> = HashMap::new();
+
+        for state in guild.voice_states.values() {
+            if let (Some(channel_id), Some((_, false))) = (state.channel_id, users.get(&state.user_id)) {
+                *humans_per_channel.entry(channel_id).or_default() += 1;
+            }
+        }
+
+        let mut stat_mans = self.stat_man.lock().unwrap();
+
+        let st = match stat_mans.get_mut(gid) {
+            Ok(st) => st,
+            Err(e) => {
+                error!(guild = gid.0, error:? = e; "failed to read stats");
+                return;
+            },
+        };
+
+        if let Err(e) = st.set_hidden_users(opted_out.clone()) {
+            error!(guild = gid.0, error:? = e; "failed to write opted out users");
+        }
+
+        for (uid, nickname) in nicknames {
+            st.set_nickname(uid, nickname);
+        }
+
+        for (uid, state) in &guild.voice_states {
+            // only names from the cache count as seen, the known one is kept for the log
+            let (observed, is_bot) = users[uid].clone();
+            let username = observed.clone().or_else(|| st.username(uid));
+
+            let verdict = match state.channel_id.and_then(|id| guild.channels.get(&id)) {
+                _ if opted_out.contains(uid) => Err(EndReason::OptedOut),
+                Some(channel) => {
+                    let channel = channel.read();
+                    let humans = humans_per_channel.get(&channel.id).cloned().unwrap_or(0);
+                    let other_humans = if is_bot { humans } else { humans.saturating_sub(1) };
+
+                    match rules.check(&channel, state, is_bot, other_humans) {
+                        None => Ok(channel.id),
+                        Some(reason) => Err(reason),
+                    }
+                },
+                None => Err(EndReason::Disconnected),
+            };
+
+            match verdict {
+                Ok(channel_id) => {
+                    if let Some(channel) = guild.channels.get(&channel_id) {
+                        st.set_channel_name(channel_id, channel.read().name.clone());
+                    }
+
+                    if st.user_now_online(*uid, channel_id, observed) {
+                        log_voice_event(gid, *uid, username.as_ref(), VoiceEvent::Joined(channel_id));
+                    }
+                },
+                Err(reason) => {
+                    if let Some(session) = st.user_now_offline(*uid, observed, reason) {
+                        log_voice_event(gid, *uid, username.as_ref(), VoiceEvent::Left(session));
+                    }
+                },
+            }
+        }
+
+        // users without a voice state left voice entirely
+        let gone: Vec<UserId> = st.online_iter()
+            .filter(|uid| !guild.voice_states.contains_key(uid))
+            .cloned()
+            .collect();
+
+        for uid in gone {
+            let username = st.username(&uid);
+
+            if let Some(session) = st.user_now_offline(uid, None, EndReason::Disconnected) {
+                log_voice_event(gid, uid, username.as_ref(), VoiceEvent::Left(session));
+            }
         }
     }
 
-    fn force_username_update_subroutine(&self, ctx: &Context, _msg: &Message, _args: &[&str]) {
-        let mut st = self.stat_man.lock().unwrap();
+    fn flip_page(&self, ctx: &Context, reaction: &Reaction) {
+        if reaction.user_id == ctx.cache.read().user.id {
+            return;
+        }
+
+        let mut all_pages = self.pages.lock().unwrap();
+
+        if let Some(PagedMessage { pages, webhook }) = all_pages.get_mut(&reaction.message_id) {
+            if pages.flip(&reaction.emoji) {
+                let edited = match webhook {
+                    Some((app_id, interaction_token)) => {
+                        let mut embed = CreateEmbed::default();
+                        pages.render(&mut embed);
+
+                        let message = slash::message(None, Some(reply::embed_json(embed)), false);
+                        slash::edit_message(&ctx.http.token, app_id, interaction_token, reaction.message_id, &message)
+                            .map_err(BotError::from)
+                    },
+                    None => reaction.channel_id
+                        .edit_message(ctx, reaction.message_id, |m| m.embed(|e| pages.render(e)))
+                        .map(|_| ())
+                        .map_err(BotError::from),
+                };
+
+                if let Err(e) = edited {
+                    warn!(channel = reaction.channel_id.0, error:? = e; "failed to flip page");
+                }
+            }
+        }
+    }
+
+    fn force_username_update_subroutine(&self, gid: GuildId, ctx: &Context, _author: &Author, out: &Responder, _args: &Args) -> BotResult<()> {
+        let mut stat_mans = self.stat_man.lock().unwrap();
+        let st = stat_mans.get_mut(gid)?;
 
         let usernames: BTreeMap<UserId, String> = st.user_iter().filter_map(|uid| {
             match uid.to_user(ctx) {
@@ -95,227 +554,829 @@ impl StatBot {
             }
         }).collect();
 
+        // users that left the guild keep their last known nickname
+        let nicknames: Vec<(UserId, Option<String>)> = usernames.keys()
+            .filter_map(|uid| gid.member(ctx, *uid).ok().map(|member| (*uid, member.nick)))
+            .collect();
+
+        for (uid, nickname) in nicknames {
+            st.set_nickname(uid, nickname);
+        }
+
         st.force_username_update(usernames);
-        println!("<{now}> Forced username update", now=Utc::now().format("%Y-%m-%d_%H:%M:%S"));
+        info!(guild = gid.0; "forced username update");
+        reply_sucess(ctx, out, "usernames and nicknames are up to date")
     }
 
-    fn stats_subroutine(&self, ctx: &Context, msg: &Message, args: &[&str]) {
-        if !args.is_empty() {
+    fn export_subroutine(&self, gid: GuildId, ctx: &Context, author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        // the scope comes first, so that the permissions of `export server` apply
+        let has_scope = matches!(args.first(), Some("me") | Some("server"));
+        let whole_server = args.first() == Some("server");
+        let format_index = if has_scope { 1 } else { 0 };
 
-            enum E {
-                ArgErr,
-                StatReadErr(crate::graphing::StatReadError)
-            }
+        args.at_most(format_index + 1)?;
 
-            let temppath = tempfile::Builder::new()
-                .suffix(".png")
-                .tempfile()
-                .unwrap()
-                .into_temp_path();
+        let format = match args.word_with(format_index, "export format", ExportFormat::from_extension) {
+            Ok(format) => format.unwrap_or(ExportFormat::Csv),
+            Err(e) => return usage(format!("{}, expected `[me|server] [csv|json]`", e)),
+        };
 
-            msg.channel_id.broadcast_typing(&ctx).unwrap();
+        let users = if whole_server {
+            None
+        } else {
+            Some(std::iter::once(author.user.id).collect())
+        };
 
-            let maybe_ok = {
-                let mut st = self.stat_man.lock().unwrap();
-                st.update_stats();
+        let rows = {
+            let mut stat_mans = self.stat_man.lock().unwrap();
+            let st = stat_mans.get_mut(gid)?;
 
-                match &args {
-                    &["graph", "total"] | &["graph"] => {
-                        let mut drawing_area = BitMapBackend::new(&temppath, (1280, 720))
-                            .into_drawing_area();
+            // the export is read from the store, so it has to be up to date
+            st.flush_stats()?;
+            export::export_rows(st.store(), users.as_ref(), st.hidden())?
+        };
 
-                        crate::graphing::time_total_graph_from_dir(st.database_path(), &mut drawing_area)
-                            .map_err(|e| E::StatReadErr(e))
-                    },
-                    /*&["graph", "time-per-day"] => st.generate_graph(false).map_err(E::IOErr),*/
-                    _ => Err(E::ArgErr)
+        if rows.is_empty() {
+            return usage("there is nothing to export yet");
+        }
+
+        let mut data = Vec::new();
+        export::write_rows(&mut data, &rows, format)?;
+
+        let filename = format!("stats_{}.{}", if whole_server { "server" } else { "me" }, format.extension());
+
+        out.send(ctx, Reply::file(filename, data))?;
+        Ok(())
+    }
+
+    fn privacy_subroutine(&self, gid: GuildId, ctx: &Context, author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        let uid = author.user.id;
+
+        args.at_most(1)?;
+
+        // the locks are released before replying, so that nothing else waits for discord
+        match args.first() {
+            Some("optout") | Some("forget") => {
+                let guild_settings = {
+                    let mut settings = self.settings.lock().unwrap();
+                    settings.guild_mut(gid).opted_out.insert(uid);
+                    self.save_settings(&settings)?;
+                    settings.guild(gid)
+                };
+
+                let forget = args.first() == Some("forget");
+
+                {
+                    let mut stat_mans = self.stat_man.lock().unwrap();
+                    let st = stat_mans.get_mut(gid)?;
+
+                    st.set_hidden_users(guild_settings.opted_out)?;
+
+                    if forget {
+                        st.forget_user(uid)?;
+                        info!(guild = gid.0, user = uid.0; "forgot user");
+                    } else if let Some(session) = st.user_now_offline(uid, Some(author.user.name.clone()), EndReason::OptedOut) {
+                        log_voice_event(gid, uid, Some(&author.user.name), VoiceEvent::Left(session));
+                    }
                 }
-            };
 
-            match maybe_ok {
-                Ok(_) => {
-                    msg.channel_id
-                        .send_files(&ctx, std::iter::once(temppath.to_str().unwrap()), |m| m)
-                        .unwrap();
-                },
-                Err(E::ArgErr) => {
-                    msg.channel_id
-                        .send_message(&ctx, |mb| mb.content(":x: Error: unknown subcommand, ('time-per-day' is not implemented yet, if you tried that) "))
-                        .unwrap();
-                },
-                Err(E::StatReadErr(e)) => {
-                    msg.channel_id
-                        .send_message(&ctx, |mb| mb.content(":x: An error occured while trying to draw graph"))
-                        .unwrap();
+                if forget {
+                    reply_sucess(ctx, out, &format!("all your stats were deleted and you are no longer tracked, use `{}privacy optin` to be tracked again", guild_settings.prefix))
+                } else {
+                    reply_sucess(ctx, out, &format!("you are no longer tracked, use `{}privacy forget` to also delete your existing stats", guild_settings.prefix))
+                }
+            },
+            Some("optin") => {
+                let guild_settings = {
+                    let mut settings = self.settings.lock().unwrap();
+
+                    if !settings.guild_mut(gid).opted_out.remove(&uid) {
+                        return usage("you are already tracked");
+                    }
 
-                    println!("E: stat graphing failed {:?}", e);
+                    self.save_settings(&settings)?;
+                    settings.guild(gid)
+                };
+
+                if let Ok(st) = self.stat_man.lock().unwrap().get_mut(gid) {
+                    st.set_hidden_users(guild_settings.opted_out)?;
                 }
+
+                reply_sucess(ctx, out, "you are tracked again from now on")
+            },
+            Some("list") => {
+                let opted_out = self.settings.lock().unwrap().guild(gid).opted_out;
+
+                let names = opted_out.iter()
+                    .map(|uid| format!("<@{}>", uid))
+                    .collect::<Vec<String>>()
+                    .join("\n");
+
+                out.send(ctx, Reply::embed(|e| {
+                    e.title("Opted out users");
+                    e.description(if names.is_empty() { "Nobody opted out".to_string() } else { names })
+                }))?;
+
+                Ok(())
+            },
+            _ => usage("expected `optout`, `optin`, `forget` or `list`"),
+        }
+    }
+
+    fn whois_subroutine(&self, gid: GuildId, ctx: &Context, _author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        let (display_name, history) = {
+            let mut stat_mans = self.stat_man.lock().unwrap();
+            let st = stat_mans.get_mut(gid)?;
+
+            match args.rest_with(0, "user", |name| resolve_member(name, st))? {
+                Some(uid) => (st.display_name(&uid), st.name_history(uid)?),
+                None => return usage("expected a user"),
             }
+        };
 
+        let names = history.iter()
+            .map(|r| {
+                let kind = match r.kind {
+                    NameKind::Username => "username",
+                    NameKind::Nickname => "nickname",
+                };
 
-        } else {
+                format!("**{}** ({}), {} to {}", r.name, kind, r.first_seen.format("%Y-%m-%d"), r.last_seen.format("%Y-%m-%d"))
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
 
-            msg.channel_id
-                .send_message(&ctx, |m| m.embed(|e| {
+        out.send(ctx, Reply::embed(|e| {
+            e.title(format!("Who is {}", display_name));
+            e.description(if names.is_empty() { "No names recorded yet".to_string() } else { names })
+        }))?;
 
-                    let mut st = self.stat_man.lock().unwrap();
-                    st.update_stats();
+        Ok(())
+    }
+
+    fn user_stats_subroutine(&self, gid: GuildId, ctx: &Context, _author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        let summary = {
+            let mut stat_mans = self.stat_man.lock().unwrap();
+            let st = stat_mans.get_mut(gid)?;
+
+            match args.rest_with(0, "user", |name| resolve_member(name, st))? {
+                Some(uid) => st.user_summary(uid)?,
+                None => return usage("expected a user"),
+            }
+        };
 
-                    e.title("Time Wasted");
+        match summary {
+            Some(summary) => {
+                out.send(ctx, Reply::embed(|e| {
+                    e.title(format!("Time Wasted by {}", summary.username));
 
-                    let sorted = {
-                        let mut buf: Vec<(UserId, (String, Duration))> = st.stats_iter()
-                            .map(|(uid, t)| (*uid, t.clone()))
-                            .collect();
+                    e.field("Total", seconds_to_discord_formatted(summary.total.as_secs()), false);
+                    e.field("Rank", format!("#{} of {}", summary.rank, summary.n_users), false);
+                    e.field("Today", seconds_to_discord_formatted(summary.today.as_secs()), false);
+                    e.field("This Week", seconds_to_discord_formatted(summary.this_week.as_secs()), false);
+                    e.field("This Month", seconds_to_discord_formatted(summary.this_month.as_secs()), false);
+                    e.field("Average per Active Day", seconds_to_discord_formatted(summary.avg_per_active_day.as_secs()), false);
 
-                        buf.sort_by(|(_, (_, t1)), (_, (_, t2))| t2.cmp(t1));
-                        buf
-                    };
+                    if let Some(longest) = summary.longest_session {
+                        e.field("Longest Session", seconds_to_discord_formatted(longest.as_secs()), false);
+                    }
 
-                    for (_, (username, dur)) in sorted {
-                        let secs = seconds_to_discord_formatted(dur.as_secs());
-                        e.field(username, secs, false);
+                    if let Some((channel_id, dur)) = summary.most_used_channel {
+                        e.field("Most Used Channel", format!("<#{}> {}", channel_id, seconds_to_discord_formatted(dur.as_secs())), false);
                     }
 
                     e
-                })).unwrap();
+                }))?;
+
+                Ok(())
+            },
+            None => usage("no stats for this user yet"),
         }
     }
 
-    fn settings_subroutine(&self, settings: &mut Settings, ctx: &Context, msg: &Message, args: &[&str]) {
+    fn top_stats_subroutine(&self, guild_settings: &GuildSettings, gid: GuildId, ctx: &Context, author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        args.at_most(1)?;
+        let window = window_from_args(args, 0)?;
+
+        let leaderboard = self.stat_man.lock().unwrap().get_mut(gid)?.leaderboard(window.clone())?;
+
+        let title = format!("Time Wasted from {} to {}", window.start.format("%Y-%m-%d"), window.end.pred().format("%Y-%m-%d"));
+
+        let fields = leaderboard.iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let secs = seconds_to_discord_formatted(entry.time.as_secs());
+                (format!("#{} {} {}", i + 1, entry.username, rank_change(i + 1, entry.prev_rank)), secs)
+            })
+            .collect();
+
+        let position = leaderboard.iter().position(|entry| entry.uid == author.user.id);
+
+        self.send_pages(ctx, out, Pages::new(title, fields, guild_settings.page_size, position_footer(position)))
+    }
+
+    fn friends_stats_subroutine(&self, guild_settings: &GuildSettings, gid: GuildId, ctx: &Context, author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        let (username, companions) = {
+            let mut stat_mans = self.stat_man.lock().unwrap();
+            let st = stat_mans.get_mut(gid)?;
 
-        let reply_sucess = |mes: &str| {
-            msg.channel_id
-                .send_message(&ctx, |mb| mb.content(format!(":white_check_mark: Success: {}", mes)))
-                .unwrap();
+            let uid = args.rest_with(0, "user", |name| resolve_member(name, st))?
+                .unwrap_or(author.user.id);
+
+            let username = st.display_name(&uid);
+
+            (username, st.companions(uid)?)
         };
 
-        let reply_err = |mes: &str| {
-            msg.channel_id
-                .send_message(&ctx, |mb| mb.content(format!(":x: Error: {}", mes)))
-                .unwrap();
+        let title = format!("Time spent together with {}", username);
+
+        let fields = companions.iter()
+            .enumerate()
+            .map(|(i, (_uid, name, time))| (format!("#{} {}", i + 1, name), seconds_to_discord_formatted(time.as_secs())))
+            .collect();
+
+        let footer = format!("{} companions", companions.len());
+
+        self.send_pages(ctx, out, Pages::new(title, fields, guild_settings.page_size, footer))
+    }
+
+    fn channels_stats_subroutine(&self, guild_settings: &GuildSettings, gid: GuildId, ctx: &Context, _author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        args.at_most(1)?;
+        let window = window_from_args(args, 0)?;
+
+        let channels = self.stat_man.lock().unwrap().get_mut(gid)?.channel_leaderboard(window.clone())?;
+
+        let title = format!("Busiest channels from {} to {}", window.start.format("%Y-%m-%d"), window.end.pred().format("%Y-%m-%d"));
+
+        let fields = channels.iter()
+            .enumerate()
+            .map(|(i, (_cid, name, time))| (format!("#{} {}", i + 1, name), seconds_to_discord_formatted(time.as_secs())))
+            .collect();
+
+        let footer = format!("{} channels", channels.len());
+
+        self.send_pages(ctx, out, Pages::new(title, fields, guild_settings.page_size, footer))
+    }
+
+    fn channel_stats_subroutine(&self, guild_settings: &GuildSettings, gid: GuildId, ctx: &Context, author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        args.at_most(2)?;
+
+        let channel_id = match args.word_with(0, "channel", parse_channel)? {
+            Some(channel_id) => channel_id,
+            None => return usage("expected a channel"),
         };
 
-        if args.is_empty() {
-            msg.channel_id
-                .send_message(&ctx, |m| {
-                    m.embed(|e| {
+        let window = window_from_args(args, 1)?;
 
-                        e.title("StatBot Settings")
-                            .description(format!("Use the command format `{}settings <option>`", settings.prefix));
+        let (name, users) = {
+            let mut stat_mans = self.stat_man.lock().unwrap();
+            let st = stat_mans.get_mut(gid)?;
 
-                        for (choice, descr) in SETTINGS_CHOICES.iter().zip(SETTINGS_CHOICES_DESCR.iter()) {
-                            e.field(
-                                descr,
-                                format!("`{}settings {}`", settings.prefix, choice), true);
-                        }
+            (st.channel_name(channel_id), st.channel_users(channel_id, window.clone())?)
+        };
 
-                        e
-                    })
-                }).unwrap();
-        } else {
-            // prefix
-            if args[0] == SETTINGS_CHOICES[0] {
-                if args.len() == 2 {
-                    settings.prefix = args[1].to_string();
-
-                    {
-                        let f = File::create(&self.settings_path).unwrap();
-                        serde_json::to_writer(f, &settings).unwrap();
-                    }
+        let title = format!("Time spent in {} from {} to {}", name, window.start.format("%Y-%m-%d"), window.end.pred().format("%Y-%m-%d"));
 
-                    reply_sucess(&format!("prefix is now '{}'", settings.prefix));
-                } else {
-                    reply_err("required exactly 1 arg");
-                }
+        let fields = users.iter()
+            .enumerate()
+            .map(|(i, (_uid, name, time))| (format!("#{} {}", i + 1, name), seconds_to_discord_formatted(time.as_secs())))
+            .collect();
+
+        let position = users.iter().position(|(uid, _, _)| *uid == author.user.id);
+
+        self.send_pages(ctx, out, Pages::new(title, fields, guild_settings.page_size, position_footer(position)))
+    }
+
+    fn graph_subroutine(&self, guild_settings: &GuildSettings, gid: GuildId, ctx: &Context, _author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        // trailing `png`/`svg` and `<width>x<height>` override the guild settings
+        let mut format = guild_settings.graph_format;
+        let mut size = guild_settings.graph_size;
+        let mut n_words = args.len();
+
+        while n_words > 0 {
+            if let Ok(Some(f)) = args.word_with(n_words - 1, "graph format", ImageFormat::from_extension) {
+                format = f;
+            } else if let Ok(Some(s)) = args.word_with(n_words - 1, "graph size", parse_graph_size) {
+                size = s;
             } else {
-                reply_err("invalid setting");
+                break;
             }
+
+            n_words -= 1;
         }
+
+        let args = args.truncated(n_words);
+
+        // the charts that can be limited to a single user take them as the remaining words
+        let chart = match args.first() {
+            None | Some("total") => {
+                args.at_most(1)?;
+                Chart::Total
+            },
+            Some("time-per-day") => {
+                args.at_most(2)?;
+
+                match args.get(1) {
+                    None | Some("grouped") => Chart::TimePerDay(BarLayout::Grouped),
+                    Some("stacked") => Chart::TimePerDay(BarLayout::Stacked),
+                    Some(layout) => return usage(format!("invalid layout `{}`, expected `grouped` or `stacked`", layout)),
+                }
+            },
+            Some("heatmap") => Chart::Heatmap,
+            Some("friends") => Chart::Pairs,
+            Some("channels") => Chart::Channels,
+            Some(chart) => return usage(format!("invalid chart `{}`, expected `total`, `time-per-day`, `heatmap`, `friends` or `channels`", chart)),
+        };
+
+        let dates = flag_window(&args)?;
+
+        let temppath = tempfile::Builder::new()
+            .suffix(&format!(".{}", format.extension()))
+            .tempfile()?
+            .into_temp_path();
+
+        out.typing(ctx);
+
+        {
+            let mut stat_mans = self.stat_man.lock().unwrap();
+            let st = stat_mans.get_mut(gid)?;
+
+            let users = args.rest_with(1, "user", |name| resolve_member(name, st))?
+                .map(|uid| std::iter::once(uid).collect());
+
+            // charts are drawn from the store, so it has to be up to date
+            st.flush_stats()?;
+
+            let filter = GraphFilter {
+                dates,
+                users,
+                prefer_nicknames: guild_settings.display_name == DisplayName::Nickname,
+                hidden: guild_settings.opted_out.clone(),
+            };
+
+            crate::graphing::render_chart(st.store(), &temppath, format, size, chart, &filter)?;
+        }
+
+        out.send(ctx, Reply::file(format!("graph.{}", format.extension()), std::fs::read(&temppath)?))?;
+        Ok(())
     }
-}
 
-impl EventHandler for StatBot {
-    fn message(&self, ctx: Context, msg: Message) {
-        if !msg.author.bot {
-            let mut settings = self.settings.lock().unwrap();
+    fn stats_subroutine(&self, guild_settings: &GuildSettings, gid: GuildId, ctx: &Context, author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        match args.first() {
+            Some("user") => self.user_stats_subroutine(gid, ctx, author, out, &args.shift()),
+            Some("top") => self.top_stats_subroutine(guild_settings, gid, ctx, author, out, &args.shift()),
+            Some("friends") => self.friends_stats_subroutine(guild_settings, gid, ctx, author, out, &args.shift()),
+            Some("channels") => self.channels_stats_subroutine(guild_settings, gid, ctx, author, out, &args.shift()),
+            Some("channel") => self.channel_stats_subroutine(guild_settings, gid, ctx, author, out, &args.shift()),
+            Some("graph") => self.graph_subroutine(guild_settings, gid, ctx, author, out, &args.shift()),
+            Some(subcommand) => usage(format!("unknown subcommand `{}`", subcommand)),
+            None => {
+                let sorted = {
+                    let mut stat_mans = self.stat_man.lock().unwrap();
+                    let st = stat_mans.get_mut(gid)?;
 
-            if msg.content.starts_with(&settings.prefix) {
-                let commandline = &msg.content[settings.prefix.len()..].split(' ')
-                    .collect::<Vec<&str>>();
+                    st.update_stats();
 
-                if commandline.is_empty() {
-                    msg.channel_id
-                        .send_message(&ctx, |m| m.content("Error: expected command"))
-                        .unwrap();
-                } else {
-                    let cmd = commandline[0];
-                    let args = &commandline[1..];
-
-                    match cmd {
-                        "stats" => self.stats_subroutine(&ctx, &msg, &args[..]),
-                        "settings" => self.settings_subroutine(&mut settings, &ctx, &msg, &args[..]),
-                        "force-username-update" => self.force_username_update_subroutine(&ctx, &msg, &args[..]),
-                        _ => (),
+                    let mut buf: Vec<(UserId, (String, Duration))> = st.stats_iter()
+                        .map(|(uid, (_username, t))| (*uid, (st.display_name(uid), *t)))
+                        .collect();
+
+                    buf.sort_by(|(_, (_, t1)), (_, (_, t2))| t2.cmp(t1));
+                    buf
+                };
+
+                let position = sorted.iter().position(|(uid, _)| *uid == author.user.id);
+
+                let fields = sorted.into_iter()
+                    .map(|(_, (username, dur))| (username, seconds_to_discord_formatted(dur.as_secs())))
+                    .collect();
+
+                self.send_pages(ctx, out, Pages::new("Time Wasted".to_string(), fields, guild_settings.page_size, position_footer(position)))
+            },
+        }
+    }
+
+    fn help_subroutine(&self, guild_settings: &GuildSettings, ctx: &Context, _author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        let prefix = &guild_settings.prefix;
+
+        if args.is_empty() {
+            out.send(ctx, Reply::embed(|e| {
+                e.title("StatBot Commands")
+                    .description(format!("Use `{}help <command>` for details", prefix));
+
+                for command in &commands::COMMANDS {
+                    let mut descr = command.description.to_string();
+
+                    match guild_settings.permissions.required(command, None) {
+                        PermissionLevel::Everyone => (),
+                        level => descr.push_str(&format!("\n*requires {}*", level.describe())),
                     }
+
+                    e.field(format!("{}{}", prefix, command.name), descr, false);
+                }
+
+                e
+            }))?;
+
+            return Ok(());
+        }
+
+        let name = args.first().unwrap_or_default();
+        let name = name.strip_prefix(prefix.as_str()).unwrap_or(name);
+
+        let command = match commands::find(name) {
+            Some(command) => command,
+            None => {
+                let hint = match commands::suggest(name) {
+                    Some(name) => format!(", did you mean `{}`?", name),
+                    None => String::new(),
+                };
+
+                return usage(format!("unknown command `{}`{}", name, hint));
+            },
+        };
+
+        let usage = if command.args.is_empty() {
+            format!("`{}{}`", prefix, command.name)
+        } else {
+            format!("`{}{} {}`", prefix, command.name, command.args)
+        };
+
+        out.send(ctx, Reply::embed(|e| {
+            e.title(format!("{}{}", prefix, command.name))
+                .description(command.description)
+                .field("Usage", usage, false)
+                .field("Permission", guild_settings.permissions.required(command, None).name(), true);
+
+            if !command.aliases.is_empty() {
+                e.field("Aliases", command.aliases.join(", "), true);
+            }
+
+            for (sub, _) in command.restricted {
+                e.field(format!("{} {}", command.name, sub), guild_settings.permissions.required(command, Some(sub)).name(), true);
+            }
+
+            if command.handler == Handler::Settings {
+                for setting in &SETTINGS {
+                    e.field(
+                        format!("{} {}", setting.emoji, setting.name),
+                        format!("`{}settings {} {}`\n{}", prefix, setting.name, setting.args, setting.description), true);
                 }
             }
+
+            e
+        }))?;
+
+        Ok(())
+    }
+
+    /// `level_held` is the permission level of the author, they can't require more than that
+    fn settings_subroutine(&self, level_held: PermissionLevel, gid: GuildId, ctx: &Context, _author: &Author, out: &Responder, args: &Args) -> BotResult<()> {
+        let prefix = self.settings.lock().unwrap().guild(gid).prefix;
+
+        let option = match args.first() {
+            Some(option) => option,
+            None => {
+                out.send(ctx, Reply::embed(|e| {
+                    e.title("StatBot Settings")
+                        .description(format!("Use the command format `{}settings <option>`", prefix));
+
+                    for setting in &SETTINGS {
+                        e.field(
+                            format!("{} {}", setting.emoji, setting.name),
+                            format!("`{}settings {} {}`\n{}", prefix, setting.name, setting.args, setting.description), true);
+                    }
+
+                    e
+                }))?;
+
+                return Ok(());
+            },
+        };
+
+        if option != "permission" && option != "roles" {
+            args.at_most(2)?;
+        }
+
+        // the settings lock is released before replying, so that other commands don't wait for discord
+        let mut settings = self.settings.lock().unwrap();
+        let guild_settings = settings.guild_mut(gid);
+
+        let message = match option {
+            "prefix" => match args.get(1) {
+                // every message would start with it, and be answered with an error
+                Some(prefix) if prefix.trim().is_empty() => return usage("the prefix can't be empty"),
+                Some(prefix) => {
+                    guild_settings.prefix = prefix.to_string();
+                    format!("prefix is now '{}'", prefix)
+                },
+                None => return usage("expected a prefix"),
+            },
+            "page-size" => match args.word::<usize>(1, "page size") {
+                Ok(Some(page_size)) if (1..=MAX_PAGE_SIZE).contains(&page_size) => {
+                    guild_settings.page_size = page_size;
+                    format!("page size is now {}", page_size)
+                },
+                Err(e) => return usage(format!("{}, page size must be a number between 1 and {}", e, MAX_PAGE_SIZE)),
+                Ok(_) => return usage(format!("page size must be a number between 1 and {}", MAX_PAGE_SIZE)),
+            },
+            "graph-format" => match args.word_with(1, "graph format", ImageFormat::from_extension) {
+                Ok(Some(format)) => {
+                    guild_settings.graph_format = format;
+                    format!("graphs are now drawn as {}", format.extension())
+                },
+                Err(e) => return usage(format!("{}, graph format must be either png or svg", e)),
+                Ok(None) => return usage("graph format must be either png or svg"),
+            },
+            "graph-size" => match args.word_with(1, "graph size", parse_graph_size) {
+                Ok(Some((width, height))) => {
+                    guild_settings.graph_size = (width, height);
+                    format!("graphs are now {}x{} pixels", width, height)
+                },
+                Err(e) => return usage(format!("{}, graph size must be `<width>x<height>` with both between {} and {}", e, MIN_GRAPH_SIZE, MAX_GRAPH_SIZE)),
+                Ok(None) => return usage(format!("graph size must be `<width>x<height>` with both between {} and {}", MIN_GRAPH_SIZE, MAX_GRAPH_SIZE)),
+            },
+            "display-name" => {
+                let display_name = args.word_with(1, "display name", |name| match name {
+                    "username" => Some(DisplayName::Username),
+                    "nickname" => Some(DisplayName::Nickname),
+                    _ => None,
+                });
+
+                match display_name {
+                    Ok(Some(display_name)) => {
+                        guild_settings.display_name = display_name;
+                        format!("users are now shown with their {}", args.get(1).unwrap_or_default())
+                    },
+                    Err(e) => return usage(format!("{}, display name must be either username or nickname", e)),
+                    Ok(None) => return usage("display name must be either username or nickname"),
+                }
+            },
+            "permission" => {
+                let expected = || format!("expected `{}settings permission <command> [subcommand] <everyone|roles|admin|owner>`", prefix);
+
+                if args.len() < 3 {
+                    return usage(expected());
+                }
+
+                let command = match args.word_with(1, "command", commands::find) {
+                    Ok(command) => command.expect("checked the number of words"),
+                    Err(e) => return usage(format!("{}, {}", e, expected())),
+                };
+
+                let level = match args.word_with(args.len() - 1, "permission level", PermissionLevel::from_name) {
+                    Ok(level) => level.expect("checked the number of words"),
+                    Err(e) => return usage(format!("{}, {}", e, expected())),
+                };
+
+                // nobody can lock themselves out by requiring more than they hold
+                if level > level_held {
+                    return usage("you can not require a higher permission level than your own");
+                }
+
+                let name = std::iter::once(command.name)
+                    .chain((2..args.len() - 1).filter_map(|i| args.get(i)))
+                    .collect::<Vec<&str>>()
+                    .join(" ");
+
+                guild_settings.permissions.commands.insert(name.clone(), level);
+                format!("`{}` now requires the {} level", name, level.name())
+            },
+            "roles" => {
+                let roles: BTreeSet<RoleId> = match (args.get(1), args.len()) {
+                    (Some("none"), 2) => BTreeSet::new(),
+                    (Some(_), _) => match args.words_with(1, "role", parse_role) {
+                        Ok(roles) => roles.into_iter().collect(),
+                        Err(e) => return usage(format!("{}, expected role mentions or ids, or `none`", e)),
+                    },
+                    (None, _) => return usage("expected role mentions or ids, or `none`"),
+                };
+
+                let n_roles = roles.len();
+                guild_settings.permissions.roles = roles;
+                format!("{} roles now hold the roles permission level", n_roles)
+            },
+            _ => return usage(format!("invalid setting `{}`", option)),
+        };
+
+        self.save_settings(&settings)?;
+        drop(settings);
+
+        reply_sucess(ctx, out, &message)
+    }
+
+    /// Runs a command, the first word of `args` is its name.
+    /// Errors are replied to the author and everything but their own mistakes is logged.
+    fn run_command(&self, ctx: &Context, author: &Author, out: &Responder, gid: GuildId, args: Result<Args, ArgError>) {
+        let e = match args {
+            Ok(args) => match self.dispatch(ctx, author, out, gid, &args) {
+                Ok(()) => return,
+                Err(e) => {
+                    if e.is_internal() {
+                        error!(guild = gid.0, channel = author.channel_id.0, user = author.user.id.0, command:? = args, error:? = e; "command failed");
+                    }
+
+                    e
+                },
+            },
+            Err(e) => e.into(),
+        };
+
+        // the reply fails as well when the bot can not send messages in the channel
+        if let Err(reply_e) = reply_err(ctx, out, &e.to_string()) {
+            error!(guild = gid.0, channel = author.channel_id.0, user = author.user.id.0, error:? = reply_e; "failed to reply with error");
         }
     }
 
-    fn ready(&self, ctx: Context, rdy: Ready) {
-        let tlof = rdy.guilds.get(0).unwrap();
-        let channels: HashMap<ChannelId, GuildChannel> = tlof.id().channels(&ctx).unwrap();
+    fn dispatch(&self, ctx: &Context, author: &Author, out: &Responder, gid: GuildId, args: &Args) -> BotResult<()> {
+        // copied, so that voice state updates don't wait for the command to finish
+        let guild_settings = self.settings.lock().unwrap().guild(gid);
 
-        let mut st = self.stat_man.lock().unwrap();
+        let cmd = match args.first() {
+            Some(cmd) => cmd,
+            None => return usage(format!("expected a command, use `{}help` to list all commands", guild_settings.prefix)),
+        };
 
-        for (_id, ch) in channels {
-            match ch.kind {
-                ChannelType::Voice if !ch.name.starts_with("AFK") => {
+        let args = args.shift();
 
-                    match ch.members(&ctx) {
-                        Ok(members) => {
-                            for m in members {
+        let command = match commands::find(cmd) {
+            Some(command) => command,
+            None => {
+                // other bots may share the prefix, so only what looks like a typo of a command is answered
+                return match commands::suggest(cmd) {
+                    Some(name) => usage(format!("unknown command `{}`, did you mean `{}{}`?", cmd, guild_settings.prefix, name)),
+                    None => Ok(()),
+                };
+            },
+        };
+
+        args.check_flags(command.flags)?;
+
+        let required = guild_settings.permissions.required(command, args.first());
+
+        let level_held = self.permission_level(&guild_settings, ctx, gid, author);
+
+        if level_held < required {
+            return usage(format!("you are not allowed to do this, it requires {}", required.describe()));
+        }
+
+        if let Ok(st) = self.stat_man.lock().unwrap().get_mut(gid) {
+            st.set_prefer_nicknames(guild_settings.display_name == DisplayName::Nickname);
+            st.set_hidden_users(guild_settings.opted_out.clone())?;
+        }
+
+        match command.handler {
+            Handler::Help => self.help_subroutine(&guild_settings, ctx, author, out, &args),
+            Handler::Stats => self.stats_subroutine(&guild_settings, gid, ctx, author, out, &args),
+            Handler::Settings => self.settings_subroutine(level_held, gid, ctx, author, out, &args),
+            Handler::ForceUsernameUpdate => self.force_username_update_subroutine(gid, ctx, author, out, &args),
+            Handler::Whois => self.whois_subroutine(gid, ctx, author, out, &args),
+            Handler::Export => self.export_subroutine(gid, ctx, author, out, &args),
+            Handler::Privacy => self.privacy_subroutine(gid, ctx, author, out, &args),
+        }
+    }
 
-                                match m.user_id().to_user(&ctx) {
-                                    Ok(user) => if !user.bot { st.user_now_online(m.user_id(), Some(user.name)); },
-                                    Err(e) => { eprintln!("E: could not determine if user with id {:?} is bot, counting anyways {:?}", m.user_id(), e); }
-                                }
+    /// Answers an application command by running it with the arguments its options stand for
+    fn interaction_create(&self, ctx: &Context, raw: serde_json::Value) {
+        let interaction: slash::Interaction = match serde_json::from_value(raw) {
+            Ok(interaction) => interaction,
+            Err(e) => {
+                error!(error:? = e; "failed to parse interaction");
+                return;
+            },
+        };
+
+        let token = &ctx.http.token;
+
+        let response = match interaction.kind {
+            slash::AUTOCOMPLETE => slash::choices(slash::autocomplete(&interaction.data)),
+            slash::APPLICATION_COMMAND => {
+                let author = slash::author(&interaction)
+                    .and_then(|(user, roles)| interaction.channel_id.map(|channel_id| Author { user, roles, channel_id }));
+
+                match (interaction.guild_id, author, slash::args(&interaction.data)) {
+                    (Some(gid), Some(author), Some(args)) => {
+                        let out = Responder::interaction(&interaction);
+
+                        // discord only waits three seconds for the response, rendering can take longer
+                        if slash::takes_long(&interaction.data) {
+                            if let Err(e) = out.defer(ctx) {
+                                error!(error:? = e; "failed to respond to interaction");
+                                return;
                             }
-                        },
-                        Err(_) => {
-                            eprintln!("E: failed to enumerate members for channel {:?}", ch.name)
                         }
-                    }
-                },
-                _ => (),
+
+                        self.run_command(ctx, &author, &out, gid, Ok(args));
+                        out.finish(ctx);
+                        return;
+                    },
+                    (None, _, _) => slash::ephemeral_message(":x: Error: commands only work in servers"),
+                    _ => slash::ephemeral_message(":x: Error: unknown command"),
+                }
+            },
+            _ => return,
+        };
+
+        if let Err(e) = slash::respond(token, &interaction, &response) {
+            error!(error:? = e; "failed to respond to interaction");
+        }
+    }
+}
+
+impl EventHandler for StatBot {
+    fn message(&self, ctx: Context, msg: Message) {
+        if let (false, Some(gid)) = (msg.author.bot, msg.guild_id) {
+            let prefix = self.settings.lock().unwrap().guild(gid).prefix;
+
+            if let Some(commandline) = msg.content.strip_prefix(&prefix) {
+                self.run_command(&ctx, &Author::of_message(&msg), &Responder::Channel(msg.channel_id), gid, Args::parse(commandline));
             }
         }
+    }
 
-        println!("<{}> scan complete, now online", Utc::now().format("%Y-%m-%d_%H:%M:%S"));
+    fn unknown(&self, ctx: Context, name: String, raw: serde_json::Value) {
+        // serenity does not know about interactions yet
+        if name == "INTERACTION_CREATE" {
+            self.interaction_create(&ctx, raw);
+        }
     }
 
-    fn voice_state_update(&self, ctx: Context, _: Option<GuildId>, _old: Option<VoiceState>, new: VoiceState) {
+    fn ready(&self, ctx: Context, rdy: Ready) {
+        match ctx.http.get_current_application_info() {
+            Ok(info) => {
+                *self.app_id.lock().unwrap() = Some(info.id);
+                *self.app_owner.lock().unwrap() = Some(info.owner.id);
+
+                for guild in &rdy.guilds {
+                    if let Err(e) = slash::register_commands(&ctx.http.token, info.id, guild.id()) {
+                        error!(guild = guild.id().0, error:? = e; "failed to register slash commands");
+                    }
+                }
+            },
+            Err(e) => error!(error:? = e; "failed to fetch the bot application"),
+        }
 
-        let username = new.user_id.to_user(&ctx).map(|u| u.name).ok();
+        {
+            let mut stat_mans = self.stat_man.lock().unwrap();
 
-        match new.channel_id {
-            Some(id) if !id.name(&ctx).unwrap().starts_with("AFK") && !new.deaf && !new.self_deaf => {
-                let state_changed = self.stat_man.lock().unwrap()
-                    .user_now_online(new.user_id, username.clone());
+            if let [guild] = &rdy.guilds[..] {
+                match stat_mans.adopt_legacy_data(guild.id()) {
+                    Ok(true) => info!(guild = guild.id().0; "moved existing stats into data directory of guild"),
+                    Ok(false) => (),
+                    Err(e) => error!(guild = guild.id().0, error:? = e; "failed to move existing stats into data directory of guild"),
+                }
+            }
 
-                if state_changed {
-                    log_user_state_change(&new.user_id, username.as_ref(), UserState::Online);
+            for guild in &rdy.guilds {
+                if let Err(e) = stat_mans.get_mut(guild.id()) {
+                    error!(guild = guild.id().0, error:? = e; "failed to read stats");
                 }
-            },
-            _ => {
-                let state_changed = self.stat_man.lock().unwrap()
-                    .user_now_offline(new.user_id, username.clone());
+            }
+        }
+
+        // guilds that are not cached yet are scanned in `cache_ready`
+        for guild in &rdy.guilds {
+            self.update_voice_states(&ctx, guild.id());
+        }
+    }
 
-                if state_changed {
-                    log_user_state_change(&new.user_id, username.as_ref(), UserState::Offline);
+    fn guild_create(&self, ctx: Context, guild: Guild, is_new: bool) {
+        // guilds the bot was in when it started are registered in `ready`
+        if !is_new {
+            return;
+        }
+
+        match *self.app_id.lock().unwrap() {
+            Some(app_id) => {
+                if let Err(e) = slash::register_commands(&ctx.http.token, app_id, guild.id) {
+                    error!(guild = guild.id.0, error:? = e; "failed to register slash commands");
                 }
             },
+            None => warn!(guild = guild.id.0; "joined guild before the bot application was known, slash commands are not registered"),
+        }
+    }
+
+    fn cache_ready(&self, ctx: Context, guilds: Vec<GuildId>) {
+        for gid in guilds {
+            self.update_voice_states(&ctx, gid);
+        }
+
+        info!("scan complete, now online");
+    }
+
+    fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        self.flip_page(&ctx, &reaction);
+    }
+
+    fn reaction_remove(&self, ctx: Context, reaction: Reaction) {
+        // reactions of others can't be removed without extra permissions, so removing one flips the page as well
+        self.flip_page(&ctx, &reaction);
+    }
+
+    fn voice_state_update(&self, ctx: Context, gid: Option<GuildId>, _old: Option<VoiceState>, _new: VoiceState) {
+        // the cache already contains the new state, so this also covers the others in the affected channels
+        if let Some(gid) = gid {
+            self.update_voice_states(&ctx, gid);
         }
     }
 }
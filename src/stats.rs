@@ -1,15 +1,15 @@
 use std::collections::btree_map::{BTreeMap, Entry};
-use std::fs::File;
-use std::io::Read;
-use std::num::ParseIntError;
+use std::collections::BTreeSet;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
-use chrono::{Date, Utc};
-use serenity::model::id::UserId;
+use chrono::{Date, DateTime, Datelike, Utc};
+use serenity::model::id::{ChannelId, GuildId, UserId};
 use thiserror::Error;
 
-const DATE_FMT_STR: &str = "%Y-%m-%d";
+use crate::sessions::{self, EndReason, Session};
+use crate::store::{self, delta_secs, JsonStore, NameKind, NameRecord, StatStore, StorageBackend, StoreError, Totals};
 
 fn unwrap_username(uid: &UserId, username: Option<String>) -> String {
     username.unwrap_or(format!("{:?}", uid))
@@ -18,45 +18,118 @@ fn unwrap_username(uid: &UserId, username: Option<String>) -> String {
 
 #[derive(Debug, Error)]
 pub enum StatParseError {
-    #[error("failed to parse user id")]
-    UserIdParseError(#[from] ParseIntError),
-    #[error("failed to parse json")]
-    JsonParseError(#[from] serde_json::Error),
-    #[error("io error")]
+    #[error("storage error: {0}")]
+    StoreError(#[from] StoreError),
+    #[error("io error: {0}")]
     IOError(#[from] std::io::Error),
 }
 
 
-#[derive(Clone)]
+/// Detailed stats of a single user, see `StatManager::user_summary`
+pub struct UserSummary {
+    pub username: String,
+    pub total: Duration,
+    pub today: Duration,
+    pub this_week: Duration,
+    pub this_month: Duration,
+    pub avg_per_active_day: Duration,
+    pub longest_session: Option<Duration>,
+    pub most_used_channel: Option<(ChannelId, Duration)>,
+    /// 1-based position in the leaderboard
+    pub rank: usize,
+    pub n_users: usize,
+}
+
+/// A row of a time windowed leaderboard, see `StatManager::leaderboard`
+pub struct LeaderboardEntry {
+    pub uid: UserId,
+    pub username: String,
+    pub time: Duration,
+    /// 1-based position in the previous window of equal length, `None` if the user was not active then
+    pub prev_rank: Option<usize>,
+}
+
+pub fn week_start(date: Date<Utc>) -> Date<Utc> {
+    date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64)
+}
+
+pub fn month_start(date: Date<Utc>) -> Date<Utc> {
+    date - chrono::Duration::days(date.day0() as i64)
+}
+
+pub fn year_start(date: Date<Utc>) -> Date<Utc> {
+    date - chrono::Duration::days(date.ordinal0() as i64)
+}
+
+/// Totals as of the end of the day before `date`, i.e. the newest snapshot before it
+fn totals_before(history: &[(Date<Utc>, Totals)], date: Date<Utc>) -> Totals {
+    history.iter()
+        .rev()
+        .find(|(d, _)| *d < date)
+        .map(|(_, totals)| totals.clone())
+        .unwrap_or_default()
+}
+
+/// Time spent since the start of `date`, relative to the newest snapshot before it
+fn secs_since(history: &[(Date<Utc>, Totals)], uid: &UserId, total: u64, date: Date<Utc>) -> u64 {
+    let before = totals_before(history, date)
+        .get(uid)
+        .cloned()
+        .unwrap_or(0);
+
+    delta_secs(before, total)
+}
+
+/// Time spent by every user between two snapshots in descending order, users without any time are left out
+fn window_secs(before: &Totals, after: &Totals) -> Vec<(UserId, u64)> {
+    let mut buf: Vec<(UserId, u64)> = after.iter()
+        .map(|(uid, secs)| (*uid, delta_secs(before.get(uid).cloned().unwrap_or(0), *secs)))
+        .filter(|(_, secs)| *secs > 0)
+        .collect();
+
+    buf.sort_by(|(_, t1), (_, t2)| t2.cmp(t1));
+    buf
+}
+
+struct OpenSession {
+    channel_id: ChannelId,
+    started_at: DateTime<Utc>,
+    since: Instant,
+}
+
 pub struct StatManager {
-    output_dir: PathBuf,
+    store: Box<dyn StatStore>,
     online_time: BTreeMap<UserId, (String, Duration)>,
-    online_since: BTreeMap<UserId, Instant>
+    online_since: BTreeMap<UserId, OpenSession>,
+    finished_sessions: Vec<Session>,
+    channel_names: BTreeMap<ChannelId, String>,
+    nicknames: BTreeMap<UserId, String>,
+    /// names users were seen with since the last flush, only those are marked as seen in the name history
+    seen_usernames: BTreeMap<UserId, String>,
+    seen_nicknames: BTreeMap<UserId, String>,
+    /// show server nicknames instead of usernames where users have one
+    prefer_nicknames: bool,
+    /// users that opted out, they are left out of everything that is shown
+    hidden: BTreeSet<UserId>,
 }
 
 impl StatManager {
-    pub fn database_path(&self) -> &Path {
-        &self.output_dir
+    pub fn store(&self) -> &dyn StatStore {
+        self.store.as_ref()
     }
 
-    fn stat_file_path(&self, date: Date<Utc>) -> PathBuf {
-        self.output_dir
-            .join(format!("stats_{}.json", date.format(DATE_FMT_STR)))
-    }
-
-    fn trans_file_path(&self) -> PathBuf {
-        self.output_dir
-            .join("trans.json")
-    }
-
-    pub fn new<OutDir>(output_dir: OutDir) -> Self
-    where
-        OutDir: AsRef<Path>,
-    {
+    pub fn new(store: Box<dyn StatStore>) -> Self {
         Self {
-            output_dir: output_dir.as_ref().to_path_buf(),
+            store,
             online_time: Default::default(),
-            online_since: Default::default()
+            online_since: Default::default(),
+            finished_sessions: Default::default(),
+            channel_names: Default::default(),
+            nicknames: Default::default(),
+            seen_usernames: Default::default(),
+            seen_nicknames: Default::default(),
+            prefer_nicknames: false,
+            hidden: Default::default(),
         }
     }
 
@@ -64,8 +137,15 @@ impl StatManager {
         self.online_time.iter().map(|(uid, _)| uid)
     }
 
+    /// Returns all users that are currently counted as online
+    pub fn online_iter(&self) -> impl Iterator<Item=&UserId> {
+        self.online_since.keys()
+    }
+
+    /// Iterates over the totals of everyone that is not hidden
     pub fn stats_iter(&self) -> impl Iterator<Item=(&UserId, &(String, Duration))> {
         self.online_time.iter()
+            .filter(move |(uid, _)| !self.hidden.contains(uid))
     }
 
     pub fn generate_translations(&self) -> BTreeMap<UserId, String> {
@@ -74,86 +154,125 @@ impl StatManager {
             .collect()
     }
 
-    fn get_stat_impl<StatR: Read, TransR: Read>(srdr: StatR, trdr: TransR) -> Result<BTreeMap<UserId, (String, Duration)>, StatParseError> {
-        let stats: BTreeMap<String, u64> = serde_json::from_reader(srdr)?;
-        let trans: BTreeMap<u64, String> = serde_json::from_reader(trdr)?;
+    /// The last known username of `uid`
+    pub fn username(&self, uid: &UserId) -> Option<String> {
+        self.online_time.get(uid).map(|(username, _)| username.clone())
+    }
 
-        stats.into_iter()
-            .map(|(uid, secs)| {
-                let parsed_uid = uid.parse::<u64>()?;
-                let username = trans.get(&parsed_uid).cloned().unwrap_or(format!("{:?}", UserId(parsed_uid)));
-                Ok((UserId::from(parsed_uid), (username, Duration::from_secs(secs))))
-            })
-            .collect()
+    pub fn nicknames(&self) -> &BTreeMap<UserId, String> {
+        &self.nicknames
     }
 
-    pub fn get_stats_unbuffered(&self, date: Date<Utc>) -> Result<BTreeMap<UserId, (String, Duration)>, StatParseError> {
-        let stats = File::open(self.stat_file_path(date))?;
-        let trans = File::open(self.trans_file_path())?;
-        Self::get_stat_impl(stats, trans)
+    /// Updates the server nickname of a user, `None` if they have none
+    pub fn set_nickname(&mut self, uid: UserId, nickname: Option<String>) {
+        match nickname {
+            Some(nickname) => {
+                self.seen_nicknames.insert(uid, nickname.clone());
+                self.nicknames.insert(uid, nickname);
+            },
+            None => { self.nicknames.remove(&uid); },
+        }
     }
 
-    pub fn read_stats(&mut self) -> Result<(), StatParseError> {
+    pub fn set_prefer_nicknames(&mut self, prefer_nicknames: bool) {
+        self.prefer_nicknames = prefer_nicknames;
+    }
 
-        let newest = std::fs::read_dir(&self.output_dir)?
-            .filter_map(|de| de.ok())
-            .map(|de| de.path())
-            .filter(|p| !p.is_dir())
-            .filter(|p| {
-                let filen = p.file_name()
-                    .and_then(|osfilename| osfilename.to_str());
+    /// Hides the users that opted out, the list is written to the store whenever it changes
+    pub fn set_hidden_users(&mut self, hidden: BTreeSet<UserId>) -> Result<(), StatParseError> {
+        if hidden != self.hidden {
+            self.store.write_opted_out(&hidden)?;
+            self.hidden = hidden;
+        }
 
-                match filen {
-                    Some(filename) => filename.starts_with("stats_"),
-                    None => false
-                }
-            })
-            .max();
+        Ok(())
+    }
+
+    pub fn hidden(&self) -> &BTreeSet<UserId> {
+        &self.hidden
+    }
 
-        self.online_time = match newest {
-            Some(fp) => {
-                let stats = File::open(fp)?;
-                let trans = File::open(self.trans_file_path())?;
+    pub fn is_hidden(&self, uid: &UserId) -> bool {
+        self.hidden.contains(uid)
+    }
 
-                Self::get_stat_impl(stats, trans)?
-            },
-            None => Default::default(),
-        };
+    /// Erases everything known about a user, in memory and in the store
+    pub fn forget_user(&mut self, uid: UserId) -> Result<(), StatParseError> {
+        self.online_time.remove(&uid);
+        self.online_since.remove(&uid);
+        self.nicknames.remove(&uid);
+        self.seen_usernames.remove(&uid);
+        self.seen_nicknames.remove(&uid);
+        self.finished_sessions.retain(|s| s.user_id != uid);
+        self.store.forget_user(uid)?;
 
         Ok(())
     }
 
-    pub fn flush_stats(&mut self) -> Result<(), StatParseError> {
-        self.update_stats();
+    /// The name a user is shown with, their nickname if they have one and nicknames are preferred
+    pub fn display_name(&self, uid: &UserId) -> String {
+        match self.nicknames.get(uid) {
+            Some(nickname) if self.prefer_nicknames => nickname.clone(),
+            _ => unwrap_username(uid, self.online_time.get(uid).map(|(name, _)| name.clone())),
+        }
+    }
 
-        {
-            let f = File::create(self.stat_file_path(Utc::today()))?;
+    /// Returns every username and nickname `uid` was seen with, oldest first
+    pub fn name_history(&mut self, uid: UserId) -> Result<Vec<NameRecord>, StatParseError> {
+        // names only make it into the history when they are written
+        self.flush_stats()?;
 
-            let new: BTreeMap<String, u64> = self.online_time
-                .clone()
-                .into_iter()
-                .map(|(uid, (_username, ontime))| (format!("{}", uid), ontime.as_secs()))
-                .collect();
+        Ok(self.store.name_history()?
+            .into_iter()
+            .filter(|r| r.user_id == uid)
+            .collect())
+    }
 
-            serde_json::to_writer(f, &new)?;
-        }
+    pub fn read_stats(&mut self) -> Result<(), StatParseError> {
+        let totals = self.store.latest_totals()?;
+        let trans = self.store.usernames()?;
+
+        self.online_time = totals.into_iter()
+            .map(|(uid, secs)| {
+                let username = unwrap_username(&uid, trans.get(&uid).cloned());
+                (uid, (username, Duration::from_secs(secs)))
+            })
+            .collect();
 
-        {
-            let f = File::create(self.trans_file_path())?;
+        self.channel_names = self.store.channel_names()?;
+        self.nicknames = self.store.nicknames()?;
+        self.hidden = self.store.opted_out()?;
 
-            let trans: BTreeMap<String, String> = self.generate_translations()
-                .into_iter()
-                .map(|(uid, name)| (format!("{}", uid), name))
-                .collect();
+        Ok(())
+    }
 
-            serde_json::to_writer(f, &trans)?;
+    pub fn flush_stats(&mut self) -> Result<(), StatParseError> {
+        self.update_stats();
+
+        let totals: Totals = self.online_time
+            .iter()
+            .map(|(uid, (_username, ontime))| (*uid, ontime.as_secs()))
+            .collect();
+
+        self.store.write_totals(Utc::today(), &totals)?;
+        self.store.write_usernames(&self.generate_translations())?;
+        self.store.write_nicknames(&self.nicknames)?;
+        self.store.record_names(NameKind::Username, &self.seen_usernames)?;
+        self.store.record_names(NameKind::Nickname, &self.seen_nicknames)?;
+        self.seen_usernames.clear();
+        self.seen_nicknames.clear();
+        self.store.write_channel_names(&self.channel_names)?;
+
+        if !self.finished_sessions.is_empty() {
+            self.store.append_sessions(&self.finished_sessions)?;
+            self.finished_sessions.clear();
         }
 
         Ok(())
     }
 
     pub fn update_stats(&mut self) {
-        for (uid, timestamp) in self.online_since.iter_mut() {
+        for (uid, OpenSession { since: timestamp, .. }) in self.online_since.iter_mut() {
             let duration = Instant::now()
                 .duration_since(*timestamp);
 
@@ -166,59 +285,429 @@ impl StatManager {
         }
     }
 
-    pub fn user_now_offline(&mut self, uid: UserId, username: Option<String>) -> bool {
+    /// Remembers the username `uid` was just seen with, `None` keeps the known one
+    fn observe_username(&mut self, uid: UserId, username: Option<String>) {
+        if let Some(username) = username {
+            if let Some((name, _)) = self.online_time.get_mut(&uid) {
+                *name = username.clone();
+            }
+
+            self.seen_usernames.insert(uid, username);
+        }
+    }
 
-        let new_username = unwrap_username(&uid, username);
+    /// Ends the open session of a user and returns it, `None` if they were not online
+    pub fn user_now_offline(&mut self, uid: UserId, username: Option<String>, reason: EndReason) -> Option<Session> {
 
-        match self.online_since.remove(&uid) {
-            Some(since) => {
+        let session = match self.online_since.remove(&uid) {
+            Some(OpenSession { channel_id, started_at, since }) => {
                 let duration = Instant::now()
                     .duration_since(since);
 
+                let session = Session {
+                    user_id: uid,
+                    channel_id,
+                    start: started_at,
+                    end: Utc::now(),
+                    end_reason: reason,
+                };
+
+                self.finished_sessions.push(session.clone());
+
                 match self.online_time.get_mut(&uid) {
-                    Some((u, t)) => {
-                        *t += duration;
-
-                        if u != &new_username {
-                            *u = new_username;
-                        }
-                    },
-                    None => { self.online_time.insert(uid, (new_username, duration)); },
+                    Some((_, t)) => { *t += duration; },
+                    None => { self.online_time.insert(uid, (unwrap_username(&uid, None), duration)); },
                 }
 
-                true
+                Some(session)
             },
-            None => false
-        }
+            None => None
+        };
+
+        self.observe_username(uid, username);
+        session
     }
 
-    pub fn user_now_online(&mut self, uid: UserId, username: Option<String>) -> bool {
+    pub fn user_now_online(&mut self, uid: UserId, channel_id: ChannelId, username: Option<String>) -> bool {
 
-        let new_username = unwrap_username(&uid, username);
+        self.online_time.entry(uid)
+            .or_insert_with(|| (unwrap_username(&uid, None), Duration::from_secs(0)));
 
-        match self.online_time.get_mut(&uid) {
-            Some((name, _)) => {
-                if name != &new_username {
-                    *name = new_username;
-                }
-            },
-            None => { self.online_time.insert(uid, (new_username, Duration::from_secs(0))); }
-        }
+        self.observe_username(uid, username);
 
         match self.online_since.entry(uid) {
             Entry::Vacant(entry) => {
-                entry.insert(Instant::now());
+                entry.insert(OpenSession {
+                    channel_id,
+                    started_at: Utc::now(),
+                    since: Instant::now(),
+                });
                 true
             },
-            Entry::Occupied(_) => false
+            Entry::Occupied(mut entry) => {
+                let open = entry.get_mut();
+
+                // a move only ends the segment in the old channel, the user stays online
+                if open.channel_id != channel_id {
+                    let now = Utc::now();
+
+                    self.finished_sessions.push(Session {
+                        user_id: uid,
+                        channel_id: open.channel_id,
+                        start: open.started_at,
+                        end: now,
+                        end_reason: EndReason::Moved,
+                    });
+
+                    open.channel_id = channel_id;
+                    open.started_at = now;
+                }
+
+                false
+            }
+        }
+    }
+
+    pub fn set_channel_name(&mut self, channel_id: ChannelId, name: String) {
+        self.channel_names.insert(channel_id, name);
+    }
+
+    /// Returns the finished sessions of the store and the ones that were not flushed yet,
+    /// currently open sessions are included as if they ended now
+    fn all_sessions(&self) -> Result<Vec<Session>, StatParseError> {
+        let mut sessions = self.store.sessions()?;
+        sessions.extend(self.finished_sessions.iter().cloned());
+
+        let now = Utc::now();
+        sessions.extend(self.online_since.iter().map(|(uid, open)| Session {
+            user_id: *uid,
+            channel_id: open.channel_id,
+            start: open.started_at,
+            end: now,
+            end_reason: EndReason::Disconnected,
+        }));
+
+        sessions.retain(|s| !self.hidden.contains(&s.user_id));
+
+        Ok(sessions)
+    }
+
+    pub fn user_summary(&mut self, uid: UserId) -> Result<Option<UserSummary>, StatParseError> {
+        self.update_stats();
+
+        let (username, total) = match self.online_time.get(&uid) {
+            Some(_) if self.is_hidden(&uid) => return Ok(None),
+            Some((_name, total)) => (self.display_name(&uid), total.as_secs()),
+            None => return Ok(None),
+        };
+
+        let today = Utc::today();
+        let history = match self.store.available_dates()? {
+            Some(dates) => self.store.totals(dates.start..today)?,
+            None => Vec::new(),
+        };
+
+        let active_days = history.iter()
+            .map(|(_, totals)| totals.get(&uid).cloned().unwrap_or(0))
+            .chain(std::iter::once(total))
+            .fold((0u64, 0u64), |(active_days, prev), cur| {
+                if cur > prev || (cur < prev && cur > 0) {
+                    (active_days + 1, cur)
+                } else {
+                    (active_days, cur)
+                }
+            }).0;
+
+        let sessions: Vec<Session> = self.all_sessions()?
+            .into_iter()
+            .filter(|s| s.user_id == uid)
+            .collect();
+
+        let longest_session = sessions.iter()
+            .filter_map(|s| (s.end - s.start).to_std().ok())
+            .max();
+
+        let most_used_channel = {
+            let mut per_channel: BTreeMap<ChannelId, Duration> = BTreeMap::new();
+
+            for s in &sessions {
+                if let Ok(d) = (s.end - s.start).to_std() {
+                    *per_channel.entry(s.channel_id).or_default() += d;
+                }
+            }
+
+            per_channel.into_iter().max_by_key(|(_, d)| *d)
+        };
+
+        let rank = 1 + self.stats_iter()
+            .filter(|(_, (_, t))| t.as_secs() > total)
+            .count();
+
+        Ok(Some(UserSummary {
+            username,
+            total: Duration::from_secs(total),
+            today: Duration::from_secs(secs_since(&history, &uid, total, today)),
+            this_week: Duration::from_secs(secs_since(&history, &uid, total, week_start(today))),
+            this_month: Duration::from_secs(secs_since(&history, &uid, total, month_start(today))),
+            avg_per_active_day: Duration::from_secs(total / active_days.max(1)),
+            longest_session,
+            most_used_channel,
+            rank,
+            n_users: self.stats_iter().count(),
+        }))
+    }
+
+    /// Ranks the users by the time they spent within `dates`,
+    /// which may reach into the future, the current totals are used for today
+    pub fn leaderboard(&mut self, dates: Range<Date<Utc>>) -> Result<Vec<LeaderboardEntry>, StatParseError> {
+        self.update_stats();
+
+        let today = Utc::today();
+        let len = dates.end - dates.start;
+        let prev_start = dates.start - len;
+
+        let history = match self.store.available_dates()? {
+            Some(available) => self.store.totals(available.start..today)?,
+            None => Vec::new(),
+        };
+
+        let live: Totals = self.online_time
+            .iter()
+            .map(|(uid, (_username, ontime))| (*uid, ontime.as_secs()))
+            .collect();
+
+        let totals_at_end = |end: Date<Utc>| if end > today { live.clone() } else { totals_before(&history, end) };
+
+        let mut current = window_secs(&totals_before(&history, dates.start), &totals_at_end(dates.end));
+        let mut prev = window_secs(&totals_before(&history, prev_start), &totals_at_end(dates.start));
+
+        current.retain(|(uid, _)| !self.hidden.contains(uid));
+        prev.retain(|(uid, _)| !self.hidden.contains(uid));
+
+        Ok(current.into_iter()
+            .map(|(uid, secs)| LeaderboardEntry {
+                uid,
+                username: self.display_name(&uid),
+                time: Duration::from_secs(secs),
+                prev_rank: prev.iter().position(|(prev_uid, _)| *prev_uid == uid).map(|pos| pos + 1),
+            })
+            .collect())
+    }
+
+    /// Returns everyone `uid` shared a voice channel with and for how long, longest first
+    pub fn companions(&mut self, uid: UserId) -> Result<Vec<(UserId, String, Duration)>, StatParseError> {
+        let mut companions: Vec<(UserId, String, Duration)> = sessions::time_together(&self.all_sessions()?)
+            .into_iter()
+            .filter_map(|((a, b), secs)| match (a == uid, b == uid) {
+                (true, _) => Some((b, secs)),
+                (_, true) => Some((a, secs)),
+                _ => None,
+            })
+            .map(|(other, secs)| {
+                (other, self.display_name(&other), Duration::from_secs(secs))
+            })
+            .collect();
+
+        companions.sort_by_key(|(_uid, _name, time)| std::cmp::Reverse(*time));
+
+        Ok(companions)
+    }
+
+    /// Returns the time spent in each channel within `dates`, longest first
+    pub fn channel_leaderboard(&mut self, dates: Range<Date<Utc>>) -> Result<Vec<(ChannelId, String, Duration)>, StatParseError> {
+        let span = dates.start.and_hms(0, 0, 0)..dates.end.and_hms(0, 0, 0);
+        let sessions = sessions::clip(self.all_sessions()?, &span);
+
+        let mut channels: Vec<(ChannelId, String, Duration)> = sessions::time_per_channel(&sessions)
+            .into_iter()
+            .map(|(cid, secs)| (cid, self.channel_name(cid), Duration::from_secs(secs)))
+            .collect();
+
+        channels.sort_by_key(|(_cid, _name, time)| std::cmp::Reverse(*time));
+
+        Ok(channels)
+    }
+
+    /// Returns the time each user spent in `channel_id` within `dates`, longest first
+    pub fn channel_users(&mut self, channel_id: ChannelId, dates: Range<Date<Utc>>) -> Result<Vec<(UserId, String, Duration)>, StatParseError> {
+        let span = dates.start.and_hms(0, 0, 0)..dates.end.and_hms(0, 0, 0);
+
+        let mut sessions = sessions::clip(self.all_sessions()?, &span);
+        sessions.retain(|s| s.channel_id == channel_id);
+
+        let mut users: Vec<(UserId, String, Duration)> = sessions::time_per_user(&sessions)
+            .into_iter()
+            .map(|(uid, secs)| (uid, self.display_name(&uid), Duration::from_secs(secs)))
+            .collect();
+
+        users.sort_by_key(|(_uid, _name, time)| std::cmp::Reverse(*time));
+
+        Ok(users)
+    }
+
+    /// The last known name of a voice channel
+    pub fn channel_name(&self, channel_id: ChannelId) -> String {
+        self.channel_names.get(&channel_id).cloned().unwrap_or_else(|| format!("{:?}", channel_id))
+    }
+
+    /// Ends the sessions of all users that are currently online
+    pub fn end_all_sessions(&mut self, reason: EndReason) {
+        let online: Vec<UserId> = self.online_since.keys().cloned().collect();
+
+        for uid in online {
+            self.user_now_offline(uid, None, reason);
         }
     }
 
     pub fn force_username_update(&mut self, trans: BTreeMap<UserId, String>) {
         for (uid, new_name) in trans {
-            if let Some((old_name, _)) = self.online_time.get_mut(&uid) {
-                *old_name = new_name;
-            }
+            self.observe_username(uid, Some(new_name));
+        }
+    }
+}
+
+
+/// Holds one `StatManager` per guild, each one writing into its own subdirectory of `output_dir`
+pub struct GuildStatManagers {
+    output_dir: PathBuf,
+    backend: StorageBackend,
+    managers: BTreeMap<GuildId, StatManager>,
+}
+
+impl GuildStatManagers {
+    pub fn new<OutDir>(output_dir: OutDir, backend: StorageBackend) -> Self
+    where
+        OutDir: AsRef<Path>,
+    {
+        Self {
+            output_dir: output_dir.as_ref().to_path_buf(),
+            backend,
+            managers: Default::default(),
+        }
+    }
+
+    pub fn guild_data_dir(&self, gid: GuildId) -> PathBuf {
+        self.output_dir
+            .join(format!("{}", gid))
+    }
+
+    /// Returns the manager for the guild, reading its stats from disk on first access
+    pub fn get_mut(&mut self, gid: GuildId) -> Result<&mut StatManager, StatParseError> {
+        let data_dir = self.guild_data_dir(gid);
+
+        match self.managers.entry(gid) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                std::fs::create_dir_all(&data_dir)?;
+
+                let mut st = StatManager::new(store::open(self.backend, &data_dir)?);
+                st.read_stats()?;
+
+                Ok(entry.insert(st))
+            },
+        }
+    }
+
+    /// Moves data written by versions without multi guild support,
+    /// which lies directly in `output_dir`, into the data directory of the given guild.
+    /// Does nothing if the guild already has a data directory.
+    pub fn adopt_legacy_data(&self, gid: GuildId) -> std::io::Result<bool> {
+        let data_dir = self.guild_data_dir(gid);
+
+        if data_dir.exists() {
+            return Ok(false);
+        }
+
+        let legacy_files: Vec<PathBuf> = std::fs::read_dir(&self.output_dir)?
+            .filter_map(|de| de.ok())
+            .map(|de| de.path())
+            .filter(|p| p.is_file())
+            .filter(|p| {
+                let filen = p.file_name()
+                    .and_then(|osfilename| osfilename.to_str());
+
+                match filen {
+                    Some(filename) => JsonStore::is_store_file(filename),
+                    None => false
+                }
+            })
+            .collect();
+
+        if legacy_files.is_empty() {
+            return Ok(false);
+        }
+
+        std::fs::create_dir_all(&data_dir)?;
+
+        for p in legacy_files {
+            std::fs::rename(&p, data_dir.join(p.file_name().unwrap()))?;
+        }
+
+        Ok(true)
+    }
+
+    pub fn end_all_sessions(&mut self, reason: EndReason) {
+        for st in self.managers.values_mut() {
+            st.end_all_sessions(reason);
+        }
+    }
+
+    /// Flushes every guild, a failing guild does not keep the others from being saved.
+    /// Returns the errors of the guilds that failed.
+    pub fn flush_stats(&mut self) -> Vec<(GuildId, StatParseError)> {
+        self.managers.iter_mut()
+            .filter_map(|(gid, st)| st.flush_stats().err().map(|e| (*gid, e)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// every recorded name with when it was last seen, ordered by name
+    fn last_seen(st: &StatManager) -> Vec<(String, DateTime<Utc>)> {
+        let mut names: Vec<(String, DateTime<Utc>)> = st.store().name_history().unwrap()
+            .into_iter()
+            .map(|r| (r.name, r.last_seen))
+            .collect();
+
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn flush_only_marks_observed_names_as_seen() {
+        let dirs = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+
+        let mut managers = vec![
+            StatManager::new(store::open(StorageBackend::Json, dirs.0.path()).unwrap()),
+            StatManager::new(store::open(StorageBackend::Sqlite, dirs.1.path()).unwrap()),
+        ];
+
+        for st in &mut managers {
+            st.user_now_online(UserId(1), ChannelId(1), Some("alice".to_string()));
+            st.set_nickname(UserId(1), Some("al".to_string()));
+            st.flush_stats().unwrap();
+        }
+
+        let before: Vec<_> = managers.iter().map(last_seen).collect();
+
+        // the history has a resolution of seconds
+        std::thread::sleep(Duration::from_millis(1100));
+
+        for (st, before) in managers.iter_mut().zip(before) {
+            // still online and still known by both names, but nobody saw them again
+            st.flush_stats().unwrap();
+            assert_eq!(last_seen(st), before);
+
+            st.user_now_offline(UserId(1), Some("alice".to_string()), EndReason::Disconnected);
+            st.flush_stats().unwrap();
+
+            let after = last_seen(st);
+            assert_eq!(after[0], before[0]);
+            assert_eq!(after[1].0, "alice");
+            assert!(after[1].1 > before[1].1);
         }
     }
 }
@@ -0,0 +1,416 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use chrono::{Date, NaiveDate, Utc};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serenity::model::id::{ChannelId, UserId};
+
+use crate::sessions::Session;
+use crate::store::{NameKind, NameRecord, StatStore, StoreError, StoreResult, Totals, DATE_FMT_STR};
+
+const TRANS_FILE_NAME: &str = "trans.json";
+const NICKS_FILE_NAME: &str = "nicks.json";
+const NAME_HISTORY_FILE_NAME: &str = "names.json";
+const CHANNELS_FILE_NAME: &str = "channels.json";
+const SESSION_LOG_FILE_NAME: &str = "sessions.jsonl";
+const OPTED_OUT_FILE_NAME: &str = "opted_out.json";
+
+fn not_found(e: &StoreError) -> bool {
+    match e {
+        StoreError::IOError(e) => e.kind() == std::io::ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
+/// Reads a json file, returning the default value if it does not exist
+fn read_json_or_default<T: DeserializeOwned + Default>(path: &Path) -> StoreResult<T> {
+    match File::open(path) {
+        Ok(f) => Ok(serde_json::from_reader(BufReader::new(f))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Default::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes to a temporary file next to `path` and renames it into place once it is synced to disk,
+/// so a crash while writing never leaves a truncated file behind
+fn write_atomically<F>(path: &Path, write: F) -> StoreResult<()>
+where
+    F: FnOnce(&mut BufWriter<&mut File>) -> StoreResult<()>,
+{
+    // a bare file name has an empty parent
+    let dir = path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+
+    {
+        let mut w = BufWriter::new(tmp.as_file_mut());
+        write(&mut w)?;
+        w.flush()?;
+    }
+
+    tmp.as_file().sync_all()?;
+    tmp.persist(path).map_err(|e| e.error)?;
+
+    // make the rename itself durable
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+pub fn write_json_atomically<T: Serialize>(path: &Path, value: &T) -> StoreResult<()> {
+    write_atomically(path, |w| Ok(serde_json::to_writer(w, value)?))
+}
+
+/// The original storage layout, a directory with one `stats_YYYY-MM-DD.json` file per day
+/// mapping user ids to seconds, a `trans.json` mapping user ids to usernames, a `nicks.json` mapping them to nicknames,
+/// a `names.json` with the history of both, a `channels.json` mapping channel ids to names,
+/// a `sessions.jsonl` log with one session per line and an `opted_out.json` listing the users that opted out
+pub struct JsonStore {
+    dir: PathBuf,
+}
+
+impl JsonStore {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Whether the file with the given name is part of the layout
+    pub fn is_store_file(filename: &str) -> bool {
+        (filename.starts_with("stats_") && filename.ends_with(".json"))
+            || filename == TRANS_FILE_NAME
+            || filename == NICKS_FILE_NAME
+            || filename == NAME_HISTORY_FILE_NAME
+            || filename == CHANNELS_FILE_NAME
+            || filename == SESSION_LOG_FILE_NAME
+            || filename == OPTED_OUT_FILE_NAME
+    }
+
+    fn stat_file_path(&self, date: Date<Utc>) -> PathBuf {
+        self.dir
+            .join(format!("stats_{}.json", date.format(DATE_FMT_STR)))
+    }
+
+    fn trans_file_path(&self) -> PathBuf {
+        self.dir
+            .join(TRANS_FILE_NAME)
+    }
+
+    fn nicks_file_path(&self) -> PathBuf {
+        self.dir
+            .join(NICKS_FILE_NAME)
+    }
+
+    fn name_history_path(&self) -> PathBuf {
+        self.dir
+            .join(NAME_HISTORY_FILE_NAME)
+    }
+
+    /// Adds the given names to the history, `seen` also marks the ones already in there as seen now
+    fn update_name_history(&self, kind: NameKind, names: &BTreeMap<UserId, String>, seen: bool) -> StoreResult<()> {
+        let mut history: Vec<NameRecord> = read_json_or_default(&self.name_history_path())?;
+        let now = Utc::now();
+
+        for (uid, name) in names {
+            match history.iter_mut().find(|r| r.user_id == *uid && r.kind == kind && r.name == *name) {
+                Some(record) => if seen {
+                    record.last_seen = now;
+                },
+                None => history.push(NameRecord {
+                    user_id: *uid,
+                    kind,
+                    name: name.clone(),
+                    first_seen: now,
+                    last_seen: now,
+                }),
+            }
+        }
+
+        write_json_atomically(&self.name_history_path(), &history)
+    }
+
+    fn channels_file_path(&self) -> PathBuf {
+        self.dir
+            .join(CHANNELS_FILE_NAME)
+    }
+
+    fn session_log_path(&self) -> PathBuf {
+        self.dir
+            .join(SESSION_LOG_FILE_NAME)
+    }
+
+    fn opted_out_path(&self) -> PathBuf {
+        self.dir
+            .join(OPTED_OUT_FILE_NAME)
+    }
+
+    /// Returns the dates of all stat files in ascending order
+    fn stat_file_dates(&self) -> StoreResult<Vec<Date<Utc>>> {
+        let mut dates = Vec::new();
+
+        for e in std::fs::read_dir(&self.dir)? {
+            let path = e?.path();
+
+            if !path.is_file() {
+                continue;
+            }
+
+            let filestem = path.file_stem().unwrap().to_string_lossy().into_owned();
+
+            if !filestem.starts_with("stats_") {
+                continue;
+            }
+
+            if let Ok(date) = NaiveDate::parse_from_str(&filestem[6..], DATE_FMT_STR) {
+                dates.push(Date::from_utc(date, Utc));
+            }
+        }
+
+        dates.sort();
+        Ok(dates)
+    }
+
+    fn read_totals(&self, date: Date<Utc>) -> StoreResult<Totals> {
+        let f = File::open(self.stat_file_path(date))?;
+        let stats: BTreeMap<String, u64> = serde_json::from_reader(f)?;
+
+        stats.into_iter()
+            .map(|(uid, secs)| Ok((UserId(uid.parse()?), secs)))
+            .collect()
+    }
+}
+
+impl StatStore for JsonStore {
+    fn available_dates(&self) -> StoreResult<Option<Range<Date<Utc>>>> {
+        let dates = self.stat_file_dates()?;
+
+        match (dates.first(), dates.last()) {
+            (Some(first), Some(last)) => Ok(Some(*first..last.succ())),
+            _ => Ok(None),
+        }
+    }
+
+    fn latest_totals(&self) -> StoreResult<Totals> {
+        let mut last_err = None;
+
+        for date in self.stat_file_dates()?.into_iter().rev() {
+            match self.read_totals(date) {
+                Ok(totals) => return Ok(totals),
+                Err(e) => {
+                    warn!(date:% = date.format(DATE_FMT_STR), error:? = e; "stats file is unreadable, falling back to previous snapshot");
+                    last_err = Some(e);
+                },
+            }
+        }
+
+        match last_err {
+            Some(e) => Err(e),
+            None => Ok(Default::default()),
+        }
+    }
+
+    fn totals(&self, dates: Range<Date<Utc>>) -> StoreResult<Vec<(Date<Utc>, Totals)>> {
+        let mut buf = Vec::new();
+
+        for date in self.stat_file_dates()? {
+            if !dates.contains(&date) {
+                continue;
+            }
+
+            match self.read_totals(date) {
+                Ok(totals) => buf.push((date, totals)),
+                Err(e) if not_found(&e) => (),
+                Err(e @ StoreError::JsonParseError(_)) => {
+                    warn!(date:% = date.format(DATE_FMT_STR), error:? = e; "skipping unreadable stats file");
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(buf)
+    }
+
+    fn write_totals(&mut self, date: Date<Utc>, totals: &Totals) -> StoreResult<()> {
+        let totals: BTreeMap<String, u64> = totals.iter()
+            .map(|(uid, secs)| (format!("{}", uid), *secs))
+            .collect();
+
+        write_json_atomically(&self.stat_file_path(date), &totals)
+    }
+
+    fn usernames(&self) -> StoreResult<BTreeMap<UserId, String>> {
+        let f = match File::open(self.trans_file_path()) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let trans: BTreeMap<u64, String> = serde_json::from_reader(f)?;
+
+        Ok(trans.into_iter()
+            .map(|(uid, name)| (UserId(uid), name))
+            .collect())
+    }
+
+    fn write_usernames(&mut self, usernames: &BTreeMap<UserId, String>) -> StoreResult<()> {
+        let trans: BTreeMap<String, &String> = usernames.iter()
+            .map(|(uid, name)| (format!("{}", uid), name))
+            .collect();
+
+        write_json_atomically(&self.trans_file_path(), &trans)?;
+        self.update_name_history(NameKind::Username, usernames, false)
+    }
+
+    fn nicknames(&self) -> StoreResult<BTreeMap<UserId, String>> {
+        let nicks: BTreeMap<u64, String> = read_json_or_default(&self.nicks_file_path())?;
+
+        Ok(nicks.into_iter()
+            .map(|(uid, name)| (UserId(uid), name))
+            .collect())
+    }
+
+    fn write_nicknames(&mut self, nicknames: &BTreeMap<UserId, String>) -> StoreResult<()> {
+        let nicks: BTreeMap<String, &String> = nicknames.iter()
+            .map(|(uid, name)| (format!("{}", uid), name))
+            .collect();
+
+        write_json_atomically(&self.nicks_file_path(), &nicks)?;
+        self.update_name_history(NameKind::Nickname, nicknames, false)
+    }
+
+    fn record_names(&mut self, kind: NameKind, names: &BTreeMap<UserId, String>) -> StoreResult<()> {
+        self.update_name_history(kind, names, true)
+    }
+
+    fn name_history(&self) -> StoreResult<Vec<NameRecord>> {
+        let mut history: Vec<NameRecord> = read_json_or_default(&self.name_history_path())?;
+        history.sort_by_key(|r| r.first_seen);
+
+        Ok(history)
+    }
+
+    fn channel_names(&self) -> StoreResult<BTreeMap<ChannelId, String>> {
+        let f = match File::open(self.channels_file_path()) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let names: BTreeMap<u64, String> = serde_json::from_reader(f)?;
+
+        Ok(names.into_iter()
+            .map(|(cid, name)| (ChannelId(cid), name))
+            .collect())
+    }
+
+    fn write_channel_names(&mut self, names: &BTreeMap<ChannelId, String>) -> StoreResult<()> {
+        let names: BTreeMap<String, &String> = names.iter()
+            .map(|(cid, name)| (format!("{}", cid), name))
+            .collect();
+
+        write_json_atomically(&self.channels_file_path(), &names)
+    }
+
+    fn sessions(&self) -> StoreResult<Vec<Session>> {
+        let f = match File::open(self.session_log_path()) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut buf = Vec::new();
+
+        for (i, line) in BufReader::new(f).lines().enumerate() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            // a crash while appending can leave a truncated line behind, it must not make the whole log unreadable
+            match serde_json::from_str::<Session>(&line) {
+                Ok(session) => buf.push(session),
+                Err(e) => warn!(line = i + 1, error:? = e; "skipping unreadable line of session log"),
+            }
+        }
+
+        buf.sort_by_key(|s| s.start);
+        Ok(buf)
+    }
+
+    fn forget_user(&mut self, uid: UserId) -> StoreResult<()> {
+        for date in self.stat_file_dates()? {
+            match self.read_totals(date) {
+                Ok(mut totals) => {
+                    if totals.remove(&uid).is_some() {
+                        self.write_totals(date, &totals)?;
+                    }
+                },
+                Err(e @ StoreError::JsonParseError(_)) => {
+                    warn!(date:% = date.format(DATE_FMT_STR), error:? = e; "cannot remove user from unreadable stats file");
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        // written directly, the name history must not pick the names up again
+        for path in &[self.trans_file_path(), self.nicks_file_path()] {
+            let mut names: BTreeMap<String, String> = read_json_or_default(path)?;
+
+            if names.remove(&format!("{}", uid)).is_some() {
+                write_json_atomically(path, &names)?;
+            }
+        }
+
+        let mut history: Vec<NameRecord> = read_json_or_default(&self.name_history_path())?;
+        history.retain(|r| r.user_id != uid);
+        write_json_atomically(&self.name_history_path(), &history)?;
+
+        let sessions: Vec<Session> = self.sessions()?
+            .into_iter()
+            .filter(|s| s.user_id != uid)
+            .collect();
+
+        write_atomically(&self.session_log_path(), |w| {
+            for s in &sessions {
+                serde_json::to_writer(&mut *w, s)?;
+                w.write_all(b"\n")?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn append_sessions(&mut self, sessions: &[Session]) -> StoreResult<()> {
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.session_log_path())?;
+
+        let mut buf = Vec::new();
+
+        for s in sessions {
+            serde_json::to_writer(&mut buf, s)?;
+            buf.push(b'\n');
+        }
+
+        f.write_all(&buf)?;
+        f.sync_data()?;
+        Ok(())
+    }
+
+    fn opted_out(&self) -> StoreResult<BTreeSet<UserId>> {
+        read_json_or_default(&self.opted_out_path())
+    }
+
+    fn write_opted_out(&mut self, users: &BTreeSet<UserId>) -> StoreResult<()> {
+        write_json_atomically(&self.opted_out_path(), users)
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::num::ParseIntError;
+use std::ops::Range;
+use std::path::Path;
+
+use chrono::{Date, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, UserId};
+use thiserror::Error;
+
+use crate::sessions::Session;
+
+pub use crate::store::json::{write_json_atomically, JsonStore};
+pub use crate::store::sqlite::SqliteStore;
+
+mod json;
+mod sqlite;
+
+pub const DATE_FMT_STR: &str = "%Y-%m-%d";
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("failed to parse user id: {0}")]
+    UserIdParseError(#[from] ParseIntError),
+    #[error("failed to parse date: {0}")]
+    DateParseError(#[from] chrono::ParseError),
+    #[error("failed to parse json: {0}")]
+    JsonParseError(#[from] serde_json::Error),
+    #[error("sqlite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+    #[error("io error: {0}")]
+    IOError(#[from] std::io::Error),
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Cumulative online time in seconds per user
+pub type Totals = BTreeMap<UserId, u64>;
+
+/// Online time between two totals of the same user, a lower total means the counter was reset
+pub fn delta_secs(before: u64, after: u64) -> u64 {
+    if after >= before { after - before } else { after }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NameKind {
+    /// the global discord username
+    Username,
+    /// the server nickname
+    Nickname,
+}
+
+/// A name a user had at some point, see `StatStore::name_history`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NameRecord {
+    pub user_id: UserId,
+    pub kind: NameKind,
+    pub name: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// one `stats_YYYY-MM-DD.json` file per day, plus `trans.json`, `nicks.json`, `names.json`, `channels.json` and `sessions.jsonl`
+    #[default]
+    Json,
+    /// a single `stats.sqlite3` database
+    Sqlite,
+}
+
+impl std::str::FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(StorageBackend::Json),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            _ => Err(format!("unknown storage backend {:?}", s)),
+        }
+    }
+}
+
+/// Persistent storage of the collected stats of a single guild.
+///
+/// Totals are the cumulative online time in seconds per user, as of the end of the given date.
+pub trait StatStore: Send {
+    /// Returns the dates for which totals were written, `None` if there are none yet
+    fn available_dates(&self) -> StoreResult<Option<Range<Date<Utc>>>>;
+
+    /// Returns the newest totals, empty if there are none yet
+    fn latest_totals(&self) -> StoreResult<Totals>;
+
+    /// Returns the totals of every date within `dates` for which totals were written, ordered by date
+    fn totals(&self, dates: Range<Date<Utc>>) -> StoreResult<Vec<(Date<Utc>, Totals)>>;
+
+    /// Replaces the totals of `date`
+    fn write_totals(&mut self, date: Date<Utc>, totals: &Totals) -> StoreResult<()>;
+
+    /// Returns the current username of every known user
+    fn usernames(&self) -> StoreResult<BTreeMap<UserId, String>>;
+
+    /// Replaces the current usernames, names that are not part of the name history yet are added to it
+    fn write_usernames(&mut self, usernames: &BTreeMap<UserId, String>) -> StoreResult<()>;
+
+    /// Returns the current server nickname of every user that has one
+    fn nicknames(&self) -> StoreResult<BTreeMap<UserId, String>>;
+
+    /// Replaces the current nicknames, names that are not part of the name history yet are added to it
+    fn write_nicknames(&mut self, nicknames: &BTreeMap<UserId, String>) -> StoreResult<()>;
+
+    /// Marks the given names as seen now in the name history
+    fn record_names(&mut self, kind: NameKind, names: &BTreeMap<UserId, String>) -> StoreResult<()>;
+
+    /// Returns every username and nickname that was ever written, ordered by when it was first seen
+    fn name_history(&self) -> StoreResult<Vec<NameRecord>>;
+
+    /// Returns the last known name of every voice channel sessions were recorded in
+    fn channel_names(&self) -> StoreResult<BTreeMap<ChannelId, String>>;
+
+    fn write_channel_names(&mut self, names: &BTreeMap<ChannelId, String>) -> StoreResult<()>;
+
+    /// Erases every trace of a user: their totals, names and sessions
+    fn forget_user(&mut self, uid: UserId) -> StoreResult<()>;
+
+    /// Returns all finished sessions ordered by their start
+    fn sessions(&self) -> StoreResult<Vec<Session>>;
+
+    fn append_sessions(&mut self, sessions: &[Session]) -> StoreResult<()>;
+
+    /// Returns the users that opted out of being tracked, tools working on the store leave them out as well
+    fn opted_out(&self) -> StoreResult<BTreeSet<UserId>>;
+
+    fn write_opted_out(&mut self, users: &BTreeSet<UserId>) -> StoreResult<()>;
+}
+
+pub fn open<P: AsRef<Path>>(backend: StorageBackend, data_dir: P) -> StoreResult<Box<dyn StatStore>> {
+    match backend {
+        StorageBackend::Json => Ok(Box::new(JsonStore::new(data_dir))),
+        StorageBackend::Sqlite => Ok(Box::new(SqliteStore::open(data_dir.as_ref().join(sqlite::DATABASE_FILE_NAME))?)),
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::path::Path;
+
+use chrono::{Date, DateTime, NaiveDate, SecondsFormat, Utc};
+use rusqlite::{params, Connection};
+use serenity::model::id::{ChannelId, UserId};
+
+use crate::sessions::Session;
+use crate::store::{NameKind, NameRecord, StatStore, StoreResult, Totals, DATE_FMT_STR};
+
+pub const DATABASE_FILE_NAME: &str = "stats.sqlite3";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS daily_totals (
+        date TEXT NOT NULL,
+        user_id INTEGER NOT NULL,
+        seconds INTEGER NOT NULL,
+        PRIMARY KEY (date, user_id)
+    );
+
+    CREATE TABLE IF NOT EXISTS usernames (
+        user_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        first_seen TEXT NOT NULL,
+        last_seen TEXT NOT NULL,
+        PRIMARY KEY (user_id, name)
+    );
+
+    CREATE TABLE IF NOT EXISTS nicknames (
+        user_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        first_seen TEXT NOT NULL,
+        last_seen TEXT NOT NULL,
+        PRIMARY KEY (user_id, name)
+    );
+
+    CREATE TABLE IF NOT EXISTS current_nicknames (
+        user_id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS channel_names (
+        channel_id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS sessions (
+        user_id INTEGER NOT NULL,
+        channel_id INTEGER NOT NULL,
+        start TEXT NOT NULL,
+        end TEXT NOT NULL,
+        end_reason TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS sessions_start ON sessions (start);
+
+    CREATE TABLE IF NOT EXISTS opted_out (
+        user_id INTEGER PRIMARY KEY
+    );
+";
+
+fn format_date(date: Date<Utc>) -> String {
+    date.format(DATE_FMT_STR).to_string()
+}
+
+fn parse_date(s: &str) -> StoreResult<Date<Utc>> {
+    Ok(Date::from_utc(NaiveDate::parse_from_str(s, DATE_FMT_STR)?, Utc))
+}
+
+fn format_datetime(datetime: DateTime<Utc>) -> String {
+    datetime.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn parse_datetime(s: &str) -> StoreResult<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(s)?.with_timezone(&Utc))
+}
+
+/// Adds the given names to the history table `table`, `seen` also marks the ones already in there as seen now
+fn update_name_history(conn: &Connection, table: &str, names: &BTreeMap<UserId, String>, seen: bool) -> StoreResult<()> {
+    let now = format_datetime(Utc::now());
+
+    let on_conflict = if seen {
+        "DO UPDATE SET last_seen = excluded.last_seen"
+    } else {
+        "DO NOTHING"
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "INSERT INTO {} (user_id, name, first_seen, last_seen) VALUES (?1, ?2, ?3, ?3)
+         ON CONFLICT (user_id, name) {}",
+        table,
+        on_conflict,
+    ))?;
+
+    for (uid, name) in names {
+        stmt.execute(params![uid.0 as i64, name, now])?;
+    }
+
+    Ok(())
+}
+
+/// Stores everything in a single sqlite database.
+///
+/// Usernames and nicknames each have their own history table,
+/// every name a user had is stored with the time it was first and last seen.
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(Self { conn })
+    }
+
+    fn read_totals(&self, date: &str) -> StoreResult<Totals> {
+        let mut stmt = self.conn
+            .prepare("SELECT user_id, seconds FROM daily_totals WHERE date = ?1")?;
+
+        let rows = stmt.query_map(params![date], |row| {
+            Ok((UserId(row.get::<_, i64>(0)? as u64), row.get::<_, i64>(1)? as u64))
+        })?;
+
+        rows.map(|r| r.map_err(Into::into)).collect()
+    }
+}
+
+impl StatStore for SqliteStore {
+    fn available_dates(&self) -> StoreResult<Option<Range<Date<Utc>>>> {
+        let (first, last): (Option<String>, Option<String>) = self.conn
+            .query_row("SELECT MIN(date), MAX(date) FROM daily_totals", params![], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+
+        match (first, last) {
+            (Some(first), Some(last)) => Ok(Some(parse_date(&first)?..parse_date(&last)?.succ())),
+            _ => Ok(None),
+        }
+    }
+
+    fn latest_totals(&self) -> StoreResult<Totals> {
+        let latest: Option<String> = self.conn
+            .query_row("SELECT MAX(date) FROM daily_totals", params![], |row| row.get(0))?;
+
+        match latest {
+            Some(date) => self.read_totals(&date),
+            None => Ok(Default::default()),
+        }
+    }
+
+    fn totals(&self, dates: Range<Date<Utc>>) -> StoreResult<Vec<(Date<Utc>, Totals)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT date, user_id, seconds FROM daily_totals WHERE date >= ?1 AND date < ?2 ORDER BY date",
+        )?;
+
+        let rows = stmt.query_map(params![format_date(dates.start), format_date(dates.end)], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?))
+        })?;
+
+        let mut buf: Vec<(Date<Utc>, Totals)> = Vec::new();
+
+        for row in rows {
+            let (date, uid, secs) = row?;
+            let date = parse_date(&date)?;
+
+            match buf.last_mut() {
+                Some((last_date, totals)) if *last_date == date => {
+                    totals.insert(UserId(uid as u64), secs as u64);
+                },
+                _ => {
+                    let mut totals = BTreeMap::new();
+                    totals.insert(UserId(uid as u64), secs as u64);
+                    buf.push((date, totals));
+                },
+            }
+        }
+
+        Ok(buf)
+    }
+
+    fn write_totals(&mut self, date: Date<Utc>, totals: &Totals) -> StoreResult<()> {
+        let date = format_date(date);
+        let tx = self.conn.transaction()?;
+
+        tx.execute("DELETE FROM daily_totals WHERE date = ?1", params![date])?;
+
+        {
+            let mut stmt = tx.prepare("INSERT INTO daily_totals (date, user_id, seconds) VALUES (?1, ?2, ?3)")?;
+
+            for (uid, secs) in totals {
+                stmt.execute(params![date, uid.0 as i64, *secs as i64])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn usernames(&self) -> StoreResult<BTreeMap<UserId, String>> {
+        let mut stmt = self.conn
+            .prepare("SELECT user_id, name FROM usernames ORDER BY last_seen, rowid")?;
+
+        let rows = stmt.query_map(params![], |row| {
+            Ok((UserId(row.get::<_, i64>(0)? as u64), row.get::<_, String>(1)?))
+        })?;
+
+        // ordered by last_seen, so the most recent name of a user is inserted last
+        rows.map(|r| r.map_err(Into::into)).collect()
+    }
+
+    fn write_usernames(&mut self, usernames: &BTreeMap<UserId, String>) -> StoreResult<()> {
+        let tx = self.conn.transaction()?;
+        update_name_history(&tx, "usernames", usernames, false)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn nicknames(&self) -> StoreResult<BTreeMap<UserId, String>> {
+        let mut stmt = self.conn
+            .prepare("SELECT user_id, name FROM current_nicknames")?;
+
+        let rows = stmt.query_map(params![], |row| {
+            Ok((UserId(row.get::<_, i64>(0)? as u64), row.get::<_, String>(1)?))
+        })?;
+
+        rows.map(|r| r.map_err(Into::into)).collect()
+    }
+
+    fn write_nicknames(&mut self, nicknames: &BTreeMap<UserId, String>) -> StoreResult<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM current_nicknames", params![])?;
+
+        {
+            let mut stmt = tx.prepare("INSERT INTO current_nicknames (user_id, name) VALUES (?1, ?2)")?;
+
+            for (uid, name) in nicknames {
+                stmt.execute(params![uid.0 as i64, name])?;
+            }
+        }
+
+        update_name_history(&tx, "nicknames", nicknames, false)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn record_names(&mut self, kind: NameKind, names: &BTreeMap<UserId, String>) -> StoreResult<()> {
+        let table = match kind {
+            NameKind::Username => "usernames",
+            NameKind::Nickname => "nicknames",
+        };
+
+        update_name_history(&self.conn, table, names, true)
+    }
+
+    fn name_history(&self) -> StoreResult<Vec<NameRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT user_id, 'username', name, first_seen, last_seen FROM usernames
+             UNION ALL
+             SELECT user_id, 'nickname', name, first_seen, last_seen FROM nicknames
+             ORDER BY first_seen",
+        )?;
+
+        let rows = stmt.query_map(params![], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+
+        let mut buf = Vec::new();
+
+        for row in rows {
+            let (uid, kind, name, first_seen, last_seen) = row?;
+
+            buf.push(NameRecord {
+                user_id: UserId(uid as u64),
+                kind: if kind == "nickname" { NameKind::Nickname } else { NameKind::Username },
+                name,
+                first_seen: parse_datetime(&first_seen)?,
+                last_seen: parse_datetime(&last_seen)?,
+            });
+        }
+
+        Ok(buf)
+    }
+
+    fn channel_names(&self) -> StoreResult<BTreeMap<ChannelId, String>> {
+        let mut stmt = self.conn
+            .prepare("SELECT channel_id, name FROM channel_names")?;
+
+        let rows = stmt.query_map(params![], |row| {
+            Ok((ChannelId(row.get::<_, i64>(0)? as u64), row.get::<_, String>(1)?))
+        })?;
+
+        rows.map(|r| r.map_err(Into::into)).collect()
+    }
+
+    fn write_channel_names(&mut self, names: &BTreeMap<ChannelId, String>) -> StoreResult<()> {
+        let tx = self.conn.transaction()?;
+
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO channel_names (channel_id, name) VALUES (?1, ?2)
+                 ON CONFLICT (channel_id) DO UPDATE SET name = excluded.name",
+            )?;
+
+            for (cid, name) in names {
+                stmt.execute(params![cid.0 as i64, name])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn forget_user(&mut self, uid: UserId) -> StoreResult<()> {
+        let tx = self.conn.transaction()?;
+
+        for table in &["daily_totals", "usernames", "nicknames", "current_nicknames", "sessions"] {
+            tx.execute(&format!("DELETE FROM {} WHERE user_id = ?1", table), params![uid.0 as i64])?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn sessions(&self) -> StoreResult<Vec<Session>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT user_id, channel_id, start, end, end_reason FROM sessions ORDER BY start",
+        )?;
+
+        let rows = stmt.query_map(params![], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+
+        let mut buf = Vec::new();
+
+        for row in rows {
+            let (uid, channel_id, start, end, end_reason) = row?;
+
+            buf.push(Session {
+                user_id: UserId(uid as u64),
+                channel_id: ChannelId(channel_id as u64),
+                start: parse_datetime(&start)?,
+                end: parse_datetime(&end)?,
+                end_reason: serde_json::from_value(serde_json::Value::String(end_reason))?,
+            });
+        }
+
+        Ok(buf)
+    }
+
+    fn append_sessions(&mut self, sessions: &[Session]) -> StoreResult<()> {
+        let tx = self.conn.transaction()?;
+
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO sessions (user_id, channel_id, start, end, end_reason) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+
+            for s in sessions {
+                let end_reason = match serde_json::to_value(s.end_reason)? {
+                    serde_json::Value::String(s) => s,
+                    v => v.to_string(),
+                };
+
+                stmt.execute(params![
+                    s.user_id.0 as i64,
+                    s.channel_id.0 as i64,
+                    format_datetime(s.start),
+                    format_datetime(s.end),
+                    end_reason,
+                ])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn opted_out(&self) -> StoreResult<BTreeSet<UserId>> {
+        let mut stmt = self.conn
+            .prepare("SELECT user_id FROM opted_out")?;
+
+        let rows = stmt.query_map(params![], |row| Ok(UserId(row.get::<_, i64>(0)? as u64)))?;
+
+        rows.map(|r| r.map_err(Into::into)).collect()
+    }
+
+    fn write_opted_out(&mut self, users: &BTreeSet<UserId>) -> StoreResult<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM opted_out", params![])?;
+
+        {
+            let mut stmt = tx.prepare("INSERT INTO opted_out (user_id) VALUES (?1)")?;
+
+            for uid in users {
+                stmt.execute(params![uid.0 as i64])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}